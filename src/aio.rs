@@ -0,0 +1,326 @@
+//! Endpoint I/O through Linux native AIO, keeping several bulk transfers
+//! queued on the UDC instead of one per blocking read()/write().
+//!
+//! Completions are signalled through an eventfd, the same way adbd does it.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+use nix::sys::eventfd::{eventfd, EfdFlags};
+
+const IOCB_CMD_PREAD: u16 = 0;
+const IOCB_CMD_PWRITE: u16 = 1;
+const IOCB_FLAG_RESFD: u32 = 1;
+
+/// Transfers queued per direction.
+pub const DEFAULT_DEPTH: usize = 4;
+
+#[derive(Debug, Default)]
+#[repr(C)]
+struct Iocb {
+    data: u64,
+    #[cfg(target_endian = "little")]
+    key: u32,
+    #[cfg(target_endian = "little")]
+    rw_flags: u32,
+    #[cfg(target_endian = "big")]
+    rw_flags: u32,
+    #[cfg(target_endian = "big")]
+    key: u32,
+    lio_opcode: u16,
+    reqprio: i16,
+    fildes: u32,
+    buf: u64,
+    nbytes: u64,
+    offset: i64,
+    reserved2: u64,
+    flags: u32,
+    resfd: u32,
+}
+
+static_assertions::assert_eq_size!(Iocb, [u8; 64]);
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+struct IoEvent {
+    data: u64,
+    obj: u64,
+    res: i64,
+    res2: i64,
+}
+
+struct AioContext {
+    ctx: libc::c_ulong,
+    eventfd: File,
+    events: Vec<IoEvent>,
+}
+
+impl AioContext {
+    fn new(depth: usize) -> io::Result<Self> {
+        let mut ctx: libc::c_ulong = 0;
+        let ret = unsafe { libc::syscall(libc::SYS_io_setup, depth as libc::c_long, &mut ctx) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = eventfd(0, EfdFlags::EFD_CLOEXEC)?;
+        Ok(Self {
+            ctx,
+            eventfd: unsafe { File::from_raw_fd(fd) },
+            events: vec![IoEvent::default(); depth],
+        })
+    }
+    /// The buffer has to stay untouched until its completion is reaped.
+    fn submit(&self, file: &File, opcode: u16, data: u64, buf: *const u8, len: usize) -> io::Result<()> {
+        let mut iocb = Iocb {
+            data,
+            lio_opcode: opcode,
+            fildes: file.as_raw_fd() as u32,
+            buf: buf as u64,
+            nbytes: len as u64,
+            flags: IOCB_FLAG_RESFD,
+            resfd: self.eventfd.as_raw_fd() as u32,
+            ..Iocb::default()
+        };
+        let mut iocbs = [&mut iocb as *mut Iocb];
+
+        let ret = unsafe { libc::syscall(libc::SYS_io_submit, self.ctx, 1 as libc::c_long, iocbs.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    /// Blocks until at least one transfer completes.
+    fn reap(&mut self) -> io::Result<&[IoEvent]> {
+        let mut count = [0; 8];
+        (&self.eventfd).read_exact(&mut count)?;
+        let count = (u64::from_ne_bytes(count) as usize).min(self.events.len());
+
+        let ret = unsafe {
+            libc::syscall(libc::SYS_io_getevents, self.ctx, count as libc::c_long,
+                          self.events.len() as libc::c_long, self.events.as_mut_ptr(),
+                          ptr::null_mut::<libc::timespec>())
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(&self.events[..ret as usize])
+    }
+}
+
+impl Drop for AioContext {
+    fn drop(&mut self) {
+        // Cancels and waits for whatever is still in flight
+        unsafe { libc::syscall(libc::SYS_io_destroy, self.ctx) };
+    }
+}
+
+fn event_result(ev: &IoEvent) -> io::Result<usize> {
+    if ev.res < 0 {
+        Err(io::Error::from_raw_os_error(-ev.res as i32))
+    } else {
+        Ok(ev.res as usize)
+    }
+}
+
+/// A write only counts if all of it made it out.
+fn write_result(ev: &IoEvent, len: usize) -> io::Result<()> {
+    match event_result(ev)? {
+        n if n == len => Ok(()),
+        n => Err(io::Error::new(io::ErrorKind::WriteZero, format!("Short write, {} of {} bytes", n, len))),
+    }
+}
+
+struct ReadSlot {
+    buf: Vec<u8>,
+    result: Option<io::Result<usize>>,
+}
+
+/// Reads from an OUT endpoint with `depth` transfers of `size` bytes queued.
+pub struct AioReader {
+    // Dropped first so nothing is in flight when the buffers go away
+    ctx: AioContext,
+    file: File,
+    slots: Vec<ReadSlot>,
+    next: usize,
+    pos: usize,
+}
+
+impl AioReader {
+    pub fn new(file: File, depth: usize, size: usize) -> io::Result<Self> {
+        let mut ret = Self {
+            ctx: AioContext::new(depth)?,
+            file,
+            slots: (0..depth).map(|_| ReadSlot { buf: vec![0; size], result: None }).collect(),
+            next: 0,
+            pos: 0,
+        };
+        for idx in 0..depth {
+            ret.queue(idx)?;
+        }
+        Ok(ret)
+    }
+    fn queue(&mut self, idx: usize) -> io::Result<()> {
+        let slot = &mut self.slots[idx];
+        slot.result = None;
+        self.ctx.submit(&self.file, IOCB_CMD_PREAD, idx as u64, slot.buf.as_mut_ptr(), slot.buf.len())
+    }
+}
+
+impl Read for AioReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            while self.slots[self.next].result.is_none() {
+                for ev in self.ctx.reap()? {
+                    self.slots[ev.data as usize].result = Some(event_result(ev));
+                }
+            }
+
+            let idx = self.next;
+            let len = match self.slots[idx].result.as_ref().unwrap() {
+                Ok(len) => *len,
+                Err(_) => {
+                    let err = self.slots[idx].result.take().unwrap().unwrap_err();
+                    self.next = (idx + 1) % self.slots.len();
+                    self.queue(idx)?;
+                    return Err(err);
+                },
+            };
+
+            let n = buf.len().min(len - self.pos);
+            buf[..n].copy_from_slice(&self.slots[idx].buf[self.pos..][..n]);
+            self.pos += n;
+
+            if self.pos == len {
+                self.pos = 0;
+                self.next = (idx + 1) % self.slots.len();
+                self.queue(idx)?;
+            }
+
+            // Zero length packets aren't an end of file
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+/// Writes to an IN endpoint, returning as soon as a transfer is queued.
+///
+/// Errors of queued transfers show up on a later write() or flush().
+pub struct AioWriter {
+    ctx: AioContext,
+    file: File,
+    slots: Vec<Option<Vec<u8>>>,
+    error: Option<io::Error>,
+}
+
+impl AioWriter {
+    pub fn new(file: File, depth: usize) -> io::Result<Self> {
+        Ok(Self {
+            ctx: AioContext::new(depth)?,
+            file,
+            slots: (0..depth).map(|_| None).collect(),
+            error: None,
+        })
+    }
+    fn reap(&mut self) -> io::Result<()> {
+        for ev in self.ctx.reap()? {
+            let buf = self.slots[ev.data as usize].take().unwrap();
+            if let Err(e) = write_result(ev, buf.len()) {
+                self.error.get_or_insert(e);
+            }
+        }
+        Ok(())
+    }
+    fn check_error(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Write for AioWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_error()?;
+        let idx = loop {
+            if let Some(idx) = self.slots.iter().position(Option::is_none) {
+                break idx;
+            }
+            self.reap()?;
+            self.check_error()?;
+        };
+
+        let data = buf.to_vec();
+        self.ctx.submit(&self.file, IOCB_CMD_PWRITE, idx as u64, data.as_ptr(), data.len())?;
+        self.slots[idx] = Some(data);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        while self.slots.iter().any(Option::is_some) {
+            self.reap()?;
+        }
+        self.check_error()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_completions() {
+        let ev = |res| IoEvent { res, ..IoEvent::default() };
+        assert_eq!(event_result(&ev(512)).unwrap(), 512);
+        assert_eq!(event_result(&ev(-libc::EPIPE as i64)).unwrap_err().raw_os_error(), Some(libc::EPIPE));
+
+        assert!(write_result(&ev(16), 16).is_ok());
+        assert_eq!(write_result(&ev(10), 16).unwrap_err().kind(), io::ErrorKind::WriteZero);
+        assert_eq!(write_result(&ev(-libc::ESHUTDOWN as i64), 16).unwrap_err().raw_os_error(), Some(libc::ESHUTDOWN));
+    }
+
+    #[test]
+    fn reads_short_completions_and_errors_in_order() {
+        // Sockets get read synchronously within io_submit, the timeout turns
+        // a transfer without data into an EAGAIN completion
+        let (mut host, dev) = UnixStream::pair().unwrap();
+        dev.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        host.write_all(b"hello").unwrap();
+        let mut reader = AioReader::new(File::from(OwnedFd::from(dev)), 2, 16).unwrap();
+
+        let mut buf = [0; 3];
+        let mut read = |reader: &mut AioReader| reader.read(&mut buf).map(|n| buf[..n].to_vec());
+        assert_eq!(read(&mut reader).unwrap(), b"hel");
+        host.write_all(b"ok").unwrap();
+        // Finishing the first transfer queues it again, it picks up "ok"
+        assert_eq!(read(&mut reader).unwrap(), b"lo");
+        assert_eq!(read(&mut reader).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(read(&mut reader).unwrap(), b"ok");
+        assert_eq!(read(&mut reader).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn reports_failed_writes_later() {
+        let (host, dev) = UnixStream::pair().unwrap();
+        drop(host);
+        let mut writer = AioWriter::new(File::from(OwnedFd::from(dev)), 2).unwrap();
+        assert_eq!(writer.write(b"data").unwrap(), 4);
+        assert_eq!(writer.flush().unwrap_err().raw_os_error(), Some(libc::EPIPE));
+        assert!(writer.flush().is_ok());
+
+        let (mut host, dev) = UnixStream::pair().unwrap();
+        let mut writer = AioWriter::new(File::from(OwnedFd::from(dev)), 2).unwrap();
+        for chunk in [&b"one"[..], b"two", b"three"] {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+        let mut got = [0; 11];
+        host.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"onetwothree");
+    }
+}
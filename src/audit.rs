@@ -0,0 +1,250 @@
+//! Append-only record of every service a host opened, one JSON object per
+//! line, rotated by size.
+
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use log::warn;
+use crate::logger;
+use crate::policy::Peer;
+
+/// How an OPEN went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Started,
+    Unknown,
+    Denied,
+    Failed,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Started => "started",
+            Outcome::Unknown => "unknown",
+            Outcome::Denied => "denied",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    /// Rotate once the file would grow past this many bytes.
+    max_size: u64,
+    /// Rotated files kept around, as `<path>.1` up to `<path>.<keep>`.
+    keep: usize,
+    file: Mutex<(File, u64)>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> Result<Self> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file: Mutex::new((file, size)),
+        })
+    }
+    /// Records an OPEN as the policy and registry handled it.
+    pub fn open_stream(&self, id: u32, service: &str, command: Option<&str>, peer: &Peer, outcome: Outcome) {
+        let mut line = Line::new("open");
+        line.num("stream", id as i64)
+            .peer(peer)
+            .str("service", service)
+            .opt_str("command", command)
+            .str("result", outcome.as_str());
+        self.write(line);
+    }
+    /// Records a stream that went away, with `status` if it ran a process.
+    pub fn close_stream(&self, id: u32, service: &str, command: Option<&str>, peer: &Peer,
+                        start: SystemTime, status: Option<i32>) {
+        let mut line = Line::new("close");
+        line.num("stream", id as i64)
+            .peer(peer)
+            .str("service", service)
+            .opt_str("command", command)
+            .str("start", &logger::timestamp(start));
+        if let Some(status) = status {
+            line.num("exit_status", status as i64);
+        }
+        self.write(line);
+    }
+    fn write(&self, line: Line) {
+        let line = line.finish();
+        let mut file = self.file.lock().unwrap();
+        if file.1 > 0 && file.1 + line.len() as u64 > self.max_size {
+            match self.rotate() {
+                Ok(new) => *file = (new, 0),
+                Err(e) => warn!("Failed to rotate the audit log: {:#}", e),
+            }
+        }
+        match file.0.write_all(line.as_bytes()) {
+            Ok(()) => file.1 += line.len() as u64,
+            Err(e) => warn!("Failed to write the audit log: {}", e),
+        }
+    }
+    fn rotate(&self) -> Result<File> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        open_append(&self.path)
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to open audit log {:?}", path))
+}
+
+/// One JSON object.
+struct Line(String);
+
+impl Line {
+    fn new(event: &str) -> Self {
+        let mut ret = Self(String::from("{"));
+        ret.str("time", &logger::timestamp(SystemTime::now()))
+            .str("event", event);
+        ret
+    }
+    fn key(&mut self, key: &str) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        let _ = write!(self.0, "\"{}\":", key);
+    }
+    fn str(&mut self, key: &str, val: &str) -> &mut Self {
+        self.key(key);
+        push_json_str(&mut self.0, val);
+        self
+    }
+    fn opt_str(&mut self, key: &str, val: Option<&str>) -> &mut Self {
+        match val {
+            Some(val) => self.str(key, val),
+            None => {
+                self.key(key);
+                self.0.push_str("null");
+                self
+            },
+        }
+    }
+    fn num(&mut self, key: &str, val: i64) -> &mut Self {
+        self.key(key);
+        let _ = write!(self.0, "{}", val);
+        self
+    }
+    fn peer(&mut self, peer: &Peer) -> &mut Self {
+        self.str("transport", peer.transport)
+            .opt_str("host_key", peer.fingerprint())
+    }
+    fn finish(mut self) -> String {
+        self.0.push_str("}\n");
+        self.0
+    }
+}
+
+/// Appends `val` as a quoted JSON string.
+pub(crate) fn push_json_str(out: &mut String, val: &str) {
+    out.push('"');
+    for c in val.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = env::temp_dir().join(format!("radbd-audit-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+
+        let log = AuditLog::open(&path, 400, 2).unwrap();
+        let peer = Peer::new("tcp");
+        for id in 0..10 {
+            log.open_stream(id, "shell:echo \"hi\"", Some("bash -c 'echo \"hi\"'"), &peer, Outcome::Started);
+        }
+
+        let current = fs::read_to_string(&path).unwrap();
+        assert!(current.len() <= 400);
+        assert!(current.starts_with("{\"time\":"));
+        assert!(current.contains(r#""service":"shell:echo \"hi\"","command":"bash -c 'echo \"hi\"'","result":"started"}"#));
+        assert!(current.contains(r#""transport":"tcp","host_key":null"#));
+        assert!(dir.join("audit.log.1").exists());
+        assert!(dir.join("audit.log.2").exists());
+        assert!(!dir.join("audit.log.3").exists());
+
+        // Whole lines only, the oldest went away
+        let ids: Vec<u32> = ["audit.log.2", "audit.log.1", "audit.log"].iter()
+            .flat_map(|f| fs::read_to_string(dir.join(f)).unwrap().lines().map(untimed).collect::<Vec<_>>())
+            .map(|line| line.strip_prefix(r#""event":"open","stream":"#).unwrap()
+                 .split(',').next().unwrap().parse().unwrap())
+            .collect();
+        let kept = ids.len() as u32;
+        assert!(kept > 0 && kept < 10);
+        assert_eq!(ids, (10 - kept..10).collect::<Vec<_>>());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// `line` after its timestamp.
+    fn untimed(line: &str) -> String {
+        let rest = line.strip_prefix(r#"{"time":""#).unwrap();
+        let (time, rest) = rest.split_once("\",").unwrap();
+        assert!(time.len() == 24 && time.ends_with('Z'), "{}", line);
+        rest.to_string()
+    }
+
+    #[test]
+    fn records_open_and_close() {
+        let path = env::temp_dir().join(format!("radbd-audit-fields-{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+        let log = AuditLog::open(&path, 1 << 20, 1).unwrap();
+        let peer = Peer::new("usb");
+        let start = SystemTime::now();
+        log.open_stream(3, "exec:ls", Some("/bin/sh -c \"ls\""), &peer, Outcome::Started);
+        log.close_stream(3, "exec:ls", Some("/bin/sh -c \"ls\""), &peer, start, Some(129));
+        log.open_stream(4, "nope:", None, &peer, Outcome::Unknown);
+
+        let lines: Vec<_> = fs::read_to_string(&path).unwrap().lines().map(untimed).collect();
+        assert_eq!(lines, [
+            r#""event":"open","stream":3,"transport":"usb","host_key":null,"service":"exec:ls","command":"/bin/sh -c \"ls\"","result":"started"}"#.to_string(),
+            format!(r#""event":"close","stream":3,"transport":"usb","host_key":null,"service":"exec:ls","command":"/bin/sh -c \"ls\"","start":"{}","exit_status":129}}"#,
+                    logger::timestamp(start)),
+            r#""event":"open","stream":4,"transport":"usb","host_key":null,"service":"nope:","command":null,"result":"unknown"}"#.to_string(),
+        ]);
+
+        fs::remove_file(path).unwrap();
+    }
+}
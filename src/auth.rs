@@ -0,0 +1,212 @@
+//! Host authentication against a list of adb public keys, the way adbd checks
+//! `adb_keys`.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use md5::{Digest, Md5};
+use num_bigint::BigUint;
+
+pub const ADB_AUTH_TOKEN: u32 = 1;
+pub const ADB_AUTH_SIGNATURE: u32 = 2;
+pub const ADB_AUTH_RSAPUBLICKEY: u32 = 3;
+
+pub const TOKEN_SIZE: usize = 20;
+
+const MIN_KEY_WORDS: usize = 2048 / 32;
+
+// DER prefix of a SHA-1 DigestInfo, the host signs the token as if it was
+// a SHA-1 digest
+const SHA1_DIGEST_INFO: [u8; 15] = [
+    0x30, 0x21, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00, 0x04, 0x14,
+];
+
+/// An adb host key, as found in `adb_keys` or `~/.android/adbkey.pub`.
+#[derive(Debug, Clone)]
+pub struct PublicKey {
+    n: BigUint,
+    e: BigUint,
+    len: usize,
+    fingerprint: String,
+    comment: String,
+}
+
+impl PublicKey {
+    /// Parses `<base64 blob> [comment]`.
+    pub fn parse(line: &str) -> Result<Self> {
+        let line = line.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        let (blob, comment) = line.split_once(' ').unwrap_or((line, ""));
+        let blob = BASE64.decode(blob)
+            .context("Key isn't valid base64")?;
+
+        let word = |idx: usize| -> Result<u32> {
+            let bytes = blob.get(idx * 4..idx * 4 + 4)
+                .context("Key blob is truncated")?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        // u32 len, u32 n0inv, u32 n[len], u32 rr[len], u32 e
+        let words = word(0)? as usize;
+        // adb keys are 2048 bits, anything much shorter can't even hold the
+        // padded token
+        if !(MIN_KEY_WORDS..=1024).contains(&words) {
+            bail!("Unsupported modulus size of {} bits", words * 32);
+        }
+        let n = blob.get(8..8 + words * 4)
+            .context("Key blob is truncated")?;
+        let e = word(2 + 2 * words)?;
+
+        Ok(Self {
+            n: BigUint::from_bytes_le(n),
+            e: BigUint::from(e),
+            len: words * 4,
+            fingerprint: fingerprint(&blob),
+            comment: comment.to_string(),
+        })
+    }
+    /// MD5 of the key blob, the same one Android's authorization prompt shows.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+    /// Usually `user@host` of whoever generated the key.
+    pub fn comment(&self) -> &str {
+        &self.comment
+    }
+    /// Checks a PKCS#1 v1.5 signature of `token`.
+    pub fn verify(&self, token: &[u8], signature: &[u8]) -> bool {
+        if signature.len() != self.len {
+            return false;
+        }
+
+        let sig = BigUint::from_bytes_be(signature);
+        if sig >= self.n {
+            return false;
+        }
+        let padding = match self.len.checked_sub(3 + SHA1_DIGEST_INFO.len() + token.len()) {
+            Some(padding) => padding,
+            None => return false,
+        };
+        let em = sig.modpow(&self.e, &self.n).to_bytes_be();
+
+        let mut expected = vec![0x01];
+        expected.resize(1 + padding, 0xff);
+        expected.push(0x00);
+        expected.extend(SHA1_DIGEST_INFO);
+        expected.extend(token);
+
+        // to_bytes_be() drops the leading zero byte
+        em == expected
+    }
+}
+
+fn fingerprint(blob: &[u8]) -> String {
+    Md5::digest(blob).iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum AuthPolicy {
+    /// Every host gets in, like a userdebug build with auth disabled.
+    #[default]
+    None,
+    /// Hosts have to sign a token with one of the listed keys.
+    Keys {
+        keys: Vec<PublicKey>,
+        /// Accept keys the host offers after failing to sign with a known one.
+        accept_new: bool,
+    },
+}
+
+impl AuthPolicy {
+    pub fn from_files(files: &[PathBuf], accept_new: bool) -> Result<Self> {
+        let mut keys = Vec::new();
+        for file in files {
+            keys.extend(load_keys(file)?);
+        }
+        Ok(AuthPolicy::Keys { keys, accept_new })
+    }
+    pub fn required(&self) -> bool {
+        !matches!(self, AuthPolicy::None)
+    }
+}
+
+/// Reads an `adb_keys` file, one key per line.
+pub fn load_keys(path: &Path) -> Result<Vec<PublicKey>> {
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {:?}", path))?;
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| PublicKey::parse(line)
+            .with_context(|| format!("{}:{}: invalid key", path.display(), idx + 1)))
+        .collect()
+}
+
+pub fn new_token() -> Result<[u8; TOKEN_SIZE]> {
+    let mut ret = [0; TOKEN_SIZE];
+    File::open("/dev/urandom")?.read_exact(&mut ret)?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generated with `openssl genrsa 2048`, the signature is the raw RSA of
+    // the padded DigestInfo of TOKEN
+    const KEY: &str = concat!(
+        "QAAAAOu16Js9NekLUTSDoDxqgwnLOQ6uxUrc1JS4Wie5jdkjXBr7RT3EM3pKxoHTfZGRW+PLsxF/",
+        "xgWmS7WKLj3CYnbbqzLuG+Da8r+iS12g8Zs4x8tAfhdZT53VVV5ko+ufqd4CiMLvBwM60fOoOOi1",
+        "1hPw9NxmjnmCihmRHQIcfF3pi+TrgSngF80wi+4qTsgKnk01Kjpo1D+y5cJRFBEIIrYO8rdySoKy",
+        "HCLvGarApxqx5QDO7jgukxcZvtUncGcI/Dz2A0ehYJlFqWwfbkgfFGveGmoRAN/0P+AAHUcM+XLW",
+        "g9bjr/w5yUrPbvETkY8nL9zIqooJndTkTE4/BPLa8H1zP4SUFLZHo8Noms8qrXpXkdyUxSlQfQz5",
+        "atyaxIdkXiJPI3EGKsUILgQP12h7HPDI78VI1kldIeLeH8RSHZQysqi2QLlepVN8Xh6FgHNf2gny",
+        "LUONRY/v/xpUkXori97V4snkQmvS+BQ++3tR/Wn4BpWas532agKuysefKUL0PtEagEgiDFsiLeaH",
+        "bfDBzmYXWJdn4Py0/5lFzoquSHhMEA7Vm9NCXfuA/CmodzFcKGBml8ikzmf107aFYJM4qeuv6jzw",
+        "6jJIfLkAzQRiXX+B1MTJPC44a50UXDuW8I3mAHApR1tRBsi36NFEPHpTGzyDCY5/70LLkMsrnmOm",
+        "dbbLk730bgEAAQA=",
+        " test@radbd",
+    );
+    const SIGNATURE: &str = concat!(
+        "C29p1DnNZwXODjdoGSr316GlxRwUaQVMysNKqw8VWsj5WQ4vv6tnMw5UKENyCO293YzkCTqQY1un",
+        "GzriKl3yn7L8IT+9XmL1ZMFKkbYRxOvrex+qD/8B+shLR2PSkdHsq8wKKqfuBkfo52F5ft+wdyiy",
+        "oelGkVqiPWEXWeakTj44/mwPBESTKXGwwdrcKUBWPvL7BNttPbDHBigjqJm9b9cgo9/LPi1zZ9lW",
+        "8p2GvFwlQ7E9kPMgpmAi/U4wj8WWO8rltmt1e+t5cQoET+uaP1JJdkCS9OMgLL0BhUiAu5C2b1rU",
+        "WK8GsR8fWqA4rsiYdVaKDjOEY32i5f0jxX441Q==",
+    );
+    const TOKEN: [u8; TOKEN_SIZE] = [
+        1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+    ];
+
+    #[test]
+    fn verifies_a_signed_token() {
+        let key = PublicKey::parse(KEY).unwrap();
+        assert_eq!(key.comment(), "test@radbd");
+        assert!(key.verify(&TOKEN, &BASE64.decode(SIGNATURE).unwrap()));
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let key = PublicKey::parse(KEY).unwrap();
+        let mut signature = BASE64.decode(SIGNATURE).unwrap();
+        signature[100] ^= 1;
+        assert!(!key.verify(&TOKEN, &signature));
+        assert!(!key.verify(&[0; TOKEN_SIZE], &BASE64.decode(SIGNATURE).unwrap()));
+        assert!(!key.verify(&TOKEN, &signature[1..]));
+    }
+
+    #[test]
+    fn rejects_short_keys() {
+        // u32 len, u32 n0inv, n, rr, e of a single word modulus
+        let blob = [1u32, 1, 0xffff_fffb, 4, 3]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect::<Vec<_>>();
+        assert!(PublicKey::parse(&BASE64.encode(blob)).is_err());
+    }
+}
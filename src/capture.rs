@@ -0,0 +1,362 @@
+//! Records adb traffic to a pcapng file.
+//!
+//! Messages are wrapped in made up IPv4/TCP packets between the host at
+//! 10.0.0.1 and the device at 10.0.0.2:5555, the port Wireshark's adb
+//! dissector picks up, whatever transport they really went over.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use log::warn;
+use crate::proto::MAXDATA;
+use crate::transport::{Reader, Writer};
+
+const BLOCK_SHB: u32 = 0x0a0d0d0a;
+const BLOCK_IDB: u32 = 1;
+const BLOCK_EPB: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const LINKTYPE_IPV4: u16 = 228;
+
+const OPT_END: u16 = 0;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const HOST_ADDR: [u8; 4] = [10, 0, 0, 1];
+const DEVICE_ADDR: [u8; 4] = [10, 0, 0, 2];
+const DEVICE_PORT: u16 = 5555;
+const FIRST_HOST_PORT: u16 = 40000;
+
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH_ACK: u8 = 0x18;
+const TCP_SYN_ACK: u8 = 0x12;
+const TCP_ACK: u8 = 0x10;
+// Keeps every packet within the 16 bit IPv4 length
+const MAX_SEGMENT: usize = 65535 - 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ToDevice,
+    ToHost,
+}
+
+/// A capture file shared by every connection of a daemon.
+pub struct Capture {
+    out: Mutex<Box<dyn Write + Send>>,
+    next_port: Mutex<u16>,
+}
+
+impl Capture {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create capture {:?}", path))?;
+        Self::new(Box::new(BufWriter::new(file)))
+            .with_context(|| format!("Failed to write capture {:?}", path))
+    }
+    pub fn new(mut out: Box<dyn Write + Send>) -> Result<Self> {
+        let mut shb = Vec::new();
+        shb.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend(1u16.to_le_bytes());
+        shb.extend(0u16.to_le_bytes());
+        // Section length isn't known up front
+        shb.extend((-1i64).to_le_bytes());
+        push_option(&mut shb, OPT_SHB_USERAPPL, b"radbd");
+        push_option(&mut shb, OPT_END, &[]);
+        write_block(&mut out, BLOCK_SHB, &shb)?;
+
+        let mut idb = Vec::new();
+        idb.extend(LINKTYPE_IPV4.to_le_bytes());
+        idb.extend(0u16.to_le_bytes());
+        idb.extend(0u32.to_le_bytes());
+        push_option(&mut idb, OPT_IF_NAME, b"adb");
+        push_option(&mut idb, OPT_END, &[]);
+        write_block(&mut out, BLOCK_IDB, &idb)?;
+        out.flush()?;
+
+        Ok(Self {
+            out: Mutex::new(out),
+            next_port: Mutex::new(FIRST_HOST_PORT),
+        })
+    }
+    /// Wraps the two halves of a transport so whatever goes over them ends
+    /// up in the capture, as a TCP connection of its own.
+    pub fn wrap(self: &Arc<Self>, reader: Reader, writer: Writer) -> (Reader, Writer) {
+        let port = {
+            let mut next = self.next_port.lock().unwrap();
+            let port = *next;
+            *next = next.checked_add(1).unwrap_or(FIRST_HOST_PORT);
+            port
+        };
+        let mut flow = Flow {
+            capture: self.clone(),
+            host_port: port,
+            host_seq: 0,
+            device_seq: 0,
+        };
+        flow.handshake();
+
+        let flow = Arc::new(Mutex::new(flow));
+        let reader = CaptureReader {
+            inner: reader,
+            flow: flow.clone(),
+            framer: Framer::default(),
+        };
+        let writer = CaptureWriter {
+            inner: writer,
+            flow,
+            framer: Framer::default(),
+        };
+        (Box::new(reader), Box::new(writer))
+    }
+    fn write_packet(&self, dir: Direction, packet: &[u8]) -> io::Result<()> {
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+
+        let mut epb = Vec::new();
+        epb.extend(0u32.to_le_bytes());
+        epb.extend(((micros >> 32) as u32).to_le_bytes());
+        epb.extend((micros as u32).to_le_bytes());
+        epb.extend((packet.len() as u32).to_le_bytes());
+        epb.extend((packet.len() as u32).to_le_bytes());
+        epb.extend(packet);
+        pad(&mut epb);
+        let flags: u32 = match dir {
+            Direction::ToDevice => 1,
+            Direction::ToHost => 2,
+        };
+        push_option(&mut epb, OPT_EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut epb, OPT_END, &[]);
+
+        let mut out = self.out.lock().unwrap();
+        write_block(&mut *out, BLOCK_EPB, &epb)?;
+        out.flush()
+    }
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend(code.to_le_bytes());
+    buf.extend((value.len() as u16).to_le_bytes());
+    buf.extend(value);
+    pad(buf);
+}
+
+fn write_block(out: &mut dyn Write, ty: u32, body: &[u8]) -> io::Result<()> {
+    let len = (body.len() + 12) as u32;
+    out.write_all(&ty.to_le_bytes())?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&len.to_le_bytes())
+}
+
+/// One made up TCP connection.
+struct Flow {
+    capture: Arc<Capture>,
+    host_port: u16,
+    host_seq: u32,
+    device_seq: u32,
+}
+
+impl Flow {
+    fn handshake(&mut self) {
+        self.segment(Direction::ToDevice, TCP_SYN, &[]);
+        self.host_seq = self.host_seq.wrapping_add(1);
+        self.segment(Direction::ToHost, TCP_SYN_ACK, &[]);
+        self.device_seq = self.device_seq.wrapping_add(1);
+        self.segment(Direction::ToDevice, TCP_ACK, &[]);
+    }
+    fn record(&mut self, dir: Direction, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            self.segment(dir, TCP_PSH_ACK, chunk);
+            let seq = match dir {
+                Direction::ToDevice => &mut self.host_seq,
+                Direction::ToHost => &mut self.device_seq,
+            };
+            *seq = seq.wrapping_add(chunk.len() as u32);
+        }
+    }
+    fn segment(&self, dir: Direction, flags: u8, data: &[u8]) {
+        let (src, dst, sport, dport, seq, ack) = match dir {
+            Direction::ToDevice => (HOST_ADDR, DEVICE_ADDR, self.host_port, DEVICE_PORT, self.host_seq, self.device_seq),
+            Direction::ToHost => (DEVICE_ADDR, HOST_ADDR, DEVICE_PORT, self.host_port, self.device_seq, self.host_seq),
+        };
+        let ack = if flags == TCP_SYN { 0 } else { ack };
+
+        let mut packet = Vec::with_capacity(40 + data.len());
+        packet.extend([0x45, 0]);
+        packet.extend(((40 + data.len()) as u16).to_be_bytes());
+        packet.extend([0, 0, 0x40, 0, 64, 6, 0, 0]);
+        packet.extend(src);
+        packet.extend(dst);
+        let csum = ip_checksum(&packet);
+        packet[10..12].copy_from_slice(&csum.to_be_bytes());
+
+        packet.extend(sport.to_be_bytes());
+        packet.extend(dport.to_be_bytes());
+        packet.extend(seq.to_be_bytes());
+        packet.extend(ack.to_be_bytes());
+        packet.extend([5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend(data);
+
+        if let Err(e) = self.capture.write_packet(dir, &packet) {
+            warn!("Failed to write to the capture: {}", e);
+        }
+    }
+}
+
+fn ip_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header.chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Cuts a byte stream back into whole messages, so each one is a packet of
+/// its own however the transport chunks it.
+#[derive(Default)]
+struct Framer {
+    buf: Vec<u8>,
+}
+
+impl Framer {
+    fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buf.extend(data);
+        let mut ret = Vec::new();
+        while self.buf.len() >= 24 {
+            let len = u32::from_le_bytes(self.buf[12..16].try_into().unwrap());
+            // Garbage, record as is rather than waiting for it to complete
+            let need = if len > MAXDATA { self.buf.len() } else { 24 + len as usize };
+            if self.buf.len() < need {
+                break;
+            }
+            ret.push(self.buf.drain(..need).collect());
+        }
+        ret
+    }
+}
+
+struct CaptureReader {
+    inner: Reader,
+    flow: Arc<Mutex<Flow>>,
+    framer: Framer,
+}
+
+impl Read for CaptureReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        for msg in self.framer.push(&buf[..n]) {
+            self.flow.lock().unwrap().record(Direction::ToDevice, &msg);
+        }
+        Ok(n)
+    }
+}
+
+struct CaptureWriter {
+    inner: Writer,
+    flow: Arc<Mutex<Flow>>,
+    framer: Framer,
+}
+
+impl Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        for msg in self.framer.push(&buf[..n]) {
+            self.flow.lock().unwrap().record(Direction::ToHost, &msg);
+        }
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::proto::{self, Message};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn blocks(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut ret = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let ty = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(rest[len - 4..len], rest[4..8]);
+            ret.push((ty, &rest[8..len - 4]));
+            rest = &rest[len..];
+        }
+        ret
+    }
+
+    #[test]
+    fn records_messages_as_tcp_segments() {
+        let out = Shared::default();
+        let capture = Arc::new(Capture::new(Box::new(out.clone())).unwrap());
+
+        let (header, data) = Message::write(1, 3, b"hello".to_vec()).into_bytes();
+        let incoming = [header, data].concat();
+        let (mut reader, mut writer) = capture.wrap(Box::new(Cursor::new(incoming.clone())), Box::new(io::sink()));
+
+        let msg = proto::next_msg(&mut reader).unwrap();
+        assert_eq!(msg.data(), b"hello");
+        Message::ready(3, 1).send_to(&mut writer).unwrap();
+
+        let data = out.0.lock().unwrap().clone();
+        let blocks = blocks(&data);
+        let types: Vec<u32> = blocks.iter().map(|(ty, _)| *ty).collect();
+        assert_eq!(types, [BLOCK_SHB, BLOCK_IDB, BLOCK_EPB, BLOCK_EPB, BLOCK_EPB, BLOCK_EPB, BLOCK_EPB]);
+        assert_eq!(u16::from_le_bytes(blocks[1].1[0..2].try_into().unwrap()), LINKTYPE_IPV4);
+
+        // Message from the host, in one segment to port 5555
+        let epb = blocks[5].1;
+        let len = u32::from_le_bytes(epb[12..16].try_into().unwrap()) as usize;
+        let packet = &epb[20..20 + len];
+        assert_eq!(ip_checksum(&packet[..20]), 0);
+        assert_eq!(packet[22..24], DEVICE_PORT.to_be_bytes());
+        assert_eq!(packet[24..28], 1u32.to_be_bytes());
+        assert_eq!(packet[40..], incoming[..]);
+        // epb_flags says inbound
+        assert_eq!(epb[20 + len.next_multiple_of(4)..][..8], [2, 0, 4, 0, 1, 0, 0, 0]);
+
+        // The reply from port 5555, acking the message
+        let epb = blocks[6].1;
+        let packet = &epb[20..];
+        assert_eq!(packet[20..22], DEVICE_PORT.to_be_bytes());
+        assert_eq!(packet[28..32], (1 + incoming.len() as u32).to_be_bytes());
+        assert_eq!(packet[40..64], Message::ready(3, 1).into_bytes().0[..]);
+    }
+
+    #[test]
+    fn framer_waits_for_whole_messages() {
+        let (header, data) = Message::write(1, 3, vec![7; 10]).into_bytes();
+        let mut framer = Framer::default();
+        assert!(framer.push(&header[..10]).is_empty());
+        assert!(framer.push(&header[10..]).is_empty());
+        let mut both = data.clone();
+        both.extend(Message::ready(3, 1).into_bytes().0);
+        assert_eq!(framer.push(&both).len(), 2);
+    }
+}
@@ -0,0 +1,88 @@
+//! Per-session cgroup v2 groups, so nothing a session started can escape
+//! being killed with it.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use log::warn;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    // For enter(), which mustn't allocate after a fork
+    procs: CString,
+}
+
+impl Cgroup {
+    /// Creates a new group below `parent`, which has to be a cgroup v2
+    /// directory radbd may create groups in.
+    pub fn create(parent: &Path) -> Result<Self> {
+        let path = parent.join(format!("radbd-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir(&path)
+            .with_context(|| format!("Failed to create cgroup {:?}", path))?;
+        let procs = CString::new(path.join("cgroup.procs").as_os_str().as_bytes())?;
+        Ok(Self { path, procs })
+    }
+    /// Writes one of the group's control files, like `memory.max`.
+    pub fn set(&self, file: &str, value: &str) -> Result<()> {
+        fs::write(self.path.join(file), value)
+            .with_context(|| format!("Failed to set {} of cgroup {:?}, is its controller in the \
+                                      parent's cgroup.subtree_control?", file, self.path))
+    }
+    /// Moves the calling process into the group, meant for
+    /// [`std::os::unix::process::CommandExt::pre_exec`].
+    pub fn enter(&self) -> io::Result<()> {
+        unsafe {
+            let fd = libc::open(self.procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // "0" is whoever writes it
+            let ret = libc::write(fd, b"0".as_ptr().cast(), 1);
+            libc::close(fd);
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+    /// Kills everything in the group and removes it.
+    pub fn destroy(self) {
+        // cgroup.kill needs Linux 5.14, signal one by one before that
+        if fs::write(self.path.join("cgroup.kill"), "1").is_err() {
+            for pid in self.pids() {
+                let _ = kill(pid, Signal::SIGKILL);
+            }
+        }
+        // The group only goes away once the kernel is done with its processes
+        for _ in 0..50 {
+            match fs::remove_dir(&self.path) {
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => thread::sleep(Duration::from_millis(10)),
+                Err(e) => {
+                    warn!("Failed to remove cgroup {:?}: {}", self.path, e);
+                    return;
+                },
+                Ok(()) => return,
+            }
+        }
+        warn!("Cgroup {:?} still busy, leaving it behind", self.path);
+    }
+    fn pids(&self) -> Vec<Pid> {
+        fs::read_to_string(self.path.join("cgroup.procs"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| l.parse().ok())
+            .map(Pid::from_raw)
+            .collect()
+    }
+}
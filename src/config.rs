@@ -0,0 +1,753 @@
+//! Daemon settings, read from a TOML file and overridable from the command
+//! line.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use log::LevelFilter;
+use crate::gadget::GadgetConfig;
+use crate::logger::{self, Filter, Output};
+use crate::policy::Policy;
+use crate::privileges::{Credentials, Privileges};
+use crate::svc::framebuffer::Format;
+use crate::usb::{AdbLayout, LangStrings, UsbStrings, LANG_EN_US};
+
+/// Services radbd knows how to run, for `services.enabled`.
+pub const SERVICES: &[&str] = &["shell", "exec", "sync", "root", "logcat", "framebuffer", "reboot", "remount", "tcpip", "bench"];
+/// Services enabled unless the config says otherwise.
+pub const DEFAULT_SERVICES: &[&str] = &["shell", "exec", "sync", "root"];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub usb: UsbConfig,
+    pub tcp: TcpConfig,
+    pub banner: BannerConfig,
+    pub services: ServicesConfig,
+    pub auth: AuthConfig,
+    pub sync: SyncConfig,
+    pub shell: ShellConfig,
+    pub log: LogConfig,
+    pub capture: CaptureConfig,
+    pub policy: Policy,
+    pub privileges: PrivilegesConfig,
+    pub audit: AuditConfig,
+    /// Limits for what `shell` and `exec` run, by service name.
+    pub limits: BTreeMap<String, Limits>,
+    pub logcat: LogcatConfig,
+    pub framebuffer: FramebufferConfig,
+    pub reboot: RebootConfig,
+    pub remount: RemountConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsbConfig {
+    pub enabled: bool,
+    /// Where functionfs is mounted, also the mount point in setup mode.
+    pub functionfs: Option<PathBuf>,
+    pub aio: bool,
+    pub os_descriptors: bool,
+    /// An existing configfs gadget to write the device strings into.
+    pub gadget: Option<PathBuf>,
+    /// Creates the gadget when present.
+    pub setup: Option<SetupConfig>,
+    pub strings: Vec<StringsConfig>,
+}
+
+impl Default for UsbConfig {
+    fn default() -> Self {
+        let layout = AdbLayout::default();
+        Self {
+            enabled: true,
+            functionfs: None,
+            aio: false,
+            os_descriptors: layout.os_descs,
+            gadget: None,
+            setup: None,
+            strings: Vec::new(),
+        }
+    }
+}
+
+impl UsbConfig {
+    pub fn layout(&self) -> AdbLayout {
+        AdbLayout {
+            os_descs: self.os_descriptors,
+        }
+    }
+    pub fn usb_strings(&self) -> UsbStrings {
+        let mut ret = UsbStrings::default();
+        for s in &self.strings {
+            let lang = ret.lang_mut(s.lang);
+            if s.serial.is_some() {
+                lang.serial = s.serial.clone();
+            }
+            if s.manufacturer.is_some() {
+                lang.manufacturer = s.manufacturer.clone();
+            }
+            if s.product.is_some() {
+                lang.product = s.product.clone();
+            }
+            if let Some(interface) = &s.interface {
+                lang.interface = interface.clone();
+            }
+        }
+        ret
+    }
+    pub fn strings_mut(&mut self, lang: u16) -> &mut StringsConfig {
+        let idx = match self.strings.iter().position(|s| s.lang == lang) {
+            Some(idx) => idx,
+            None => {
+                self.strings.push(StringsConfig { lang, ..StringsConfig::default() });
+                self.strings.len() - 1
+            }
+        };
+        &mut self.strings[idx]
+    }
+    pub fn gadget_config(&self) -> Option<GadgetConfig> {
+        let setup = self.setup.as_ref()?;
+        let def = GadgetConfig::default();
+        Some(GadgetConfig {
+            configfs: setup.configfs.clone().unwrap_or(def.configfs),
+            udc_class: def.udc_class,
+            name: setup.name.clone().unwrap_or(def.name),
+            vendor_id: setup.vendor_id.unwrap_or(def.vendor_id),
+            product_id: setup.product_id.unwrap_or(def.product_id),
+            udc: setup.udc.clone(),
+            ffs_mount: self.functionfs.clone()
+                .unwrap_or_else(|| PathBuf::from("/dev/usb-ffs").join(&setup.instance)),
+            instance: setup.instance.clone(),
+            mount: setup.mount,
+            os_desc: self.os_descriptors,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SetupConfig {
+    pub configfs: Option<PathBuf>,
+    pub name: Option<String>,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub udc: Option<String>,
+    pub instance: String,
+    pub mount: bool,
+}
+
+impl Default for SetupConfig {
+    fn default() -> Self {
+        Self {
+            configfs: None,
+            name: None,
+            vendor_id: None,
+            product_id: None,
+            udc: None,
+            instance: GadgetConfig::default().instance,
+            mount: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StringsConfig {
+    pub lang: u16,
+    pub serial: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub interface: Option<String>,
+}
+
+impl Default for StringsConfig {
+    fn default() -> Self {
+        let def = LangStrings::new(LANG_EN_US);
+        Self {
+            lang: def.code,
+            serial: None,
+            manufacturer: None,
+            product: None,
+            interface: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    pub enabled: bool,
+    /// `address:port` or just a port to listen on all addresses.
+    pub listen: String,
+    /// Where `adb tcpip` and `adb usb` keep their choice, it overrides
+    /// `enabled` and the port of `listen` at startup.
+    pub state: Option<PathBuf>,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "5555".to_string(),
+            state: None,
+        }
+    }
+}
+
+impl TcpConfig {
+    pub fn addr(&self) -> String {
+        if self.listen.contains(':') {
+            self.listen.clone()
+        } else {
+            format!("0.0.0.0:{}", self.listen)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BannerConfig {
+    /// Sent to the host in CNXN, like `ro.product.model`.
+    pub properties: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServicesConfig {
+    pub enabled: Vec<String>,
+}
+
+impl Default for ServicesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: DEFAULT_SERVICES.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    #[default]
+    None,
+    Keys,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub policy: AuthMode,
+    /// `adb_keys` style files of hosts allowed in.
+    pub keys: Vec<PathBuf>,
+    /// Let in hosts that offer an unknown key.
+    pub accept_new_keys: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    /// Directories `adb push` and `adb pull` may touch.
+    pub roots: Vec<PathBuf>,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            roots: vec![PathBuf::from("/")],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShellConfig {
+    /// Shell to run, the user's login shell from passwd if unset.
+    pub default: Option<String>,
+    /// Account whose home and name the shell environment gets,
+    /// `privileges.user` if unset.
+    pub user: Option<String>,
+    /// `PATH` the shell starts with.
+    pub path: String,
+    /// `TERM` of sessions on a pty.
+    pub term: String,
+    /// Records interactive sessions for replay when present.
+    pub recording: Option<RecordingConfig>,
+    /// cgroup v2 directory each session gets a group of its own below,
+    /// so whatever it leaves running gets killed with it.
+    pub cgroup: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Gets one asciicast v2 file per session.
+    pub dir: PathBuf,
+    /// Also record what the host typed, passwords included.
+    #[serde(default)]
+    pub input: bool,
+    /// Size in bytes a recording stops at.
+    #[serde(default = "RecordingConfig::default_max_size")]
+    pub max_size: u64,
+    /// Recordings to keep, the oldest go first.
+    #[serde(default = "RecordingConfig::default_keep")]
+    pub keep: usize,
+}
+
+impl RecordingConfig {
+    fn default_max_size() -> u64 {
+        16 << 20
+    }
+    fn default_keep() -> usize {
+        100
+    }
+}
+
+impl Default for ShellConfig {
+    fn default() -> Self {
+        Self {
+            default: None,
+            user: None,
+            path: "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".to_string(),
+            term: "xterm-256color".to_string(),
+            recording: None,
+            cgroup: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogcatConfig {
+    /// Stream the kernel log from /dev/kmsg.
+    pub kernel: bool,
+    /// Log files to follow, like /var/log/syslog.
+    pub files: Vec<PathBuf>,
+}
+
+impl Default for LogcatConfig {
+    fn default() -> Self {
+        Self {
+            kernel: true,
+            files: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FramebufferConfig {
+    /// An fbdev device, a DRM card under /dev/dri, or with `mode` any file
+    /// of raw pixels.
+    pub device: PathBuf,
+    pub mode: Option<FramebufferMode>,
+}
+
+impl Default for FramebufferConfig {
+    fn default() -> Self {
+        Self {
+            device: PathBuf::from("/dev/fb0"),
+            mode: None,
+        }
+    }
+}
+
+/// What's in a raw pixel file, nothing gets asked of the device then.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FramebufferMode {
+    pub width: u32,
+    pub height: u32,
+    /// Like "rgba8888" or "rgb565".
+    pub format: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RebootConfig {
+    /// What `reboot:TARGET` does by target, "" for a plain `adb reboot`.
+    pub targets: BTreeMap<String, RebootAction>,
+}
+
+impl Default for RebootConfig {
+    fn default() -> Self {
+        let restart = |arg: &str| RebootAction::Restart(arg.to_string());
+        Self {
+            targets: BTreeMap::from([
+                (String::new(), restart("")),
+                ("bootloader".to_string(), restart("bootloader")),
+                ("recovery".to_string(), restart("recovery")),
+            ]),
+        }
+    }
+}
+
+/// Like `{ restart = "bootloader" }`, `"poweroff"` or
+/// `{ command = ["/usr/sbin/enter-recovery"] }`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RebootAction {
+    /// reboot(2), handing the bootloader this argument unless it's empty.
+    Restart(String),
+    PowerOff,
+    /// A hook that takes care of rebooting, run as radbd itself.
+    Command(Vec<String>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemountConfig {
+    /// Mount points `adb remount` makes writable.
+    pub mounts: Vec<PathBuf>,
+    /// Where overlays keep their upper and work directories, on a writable
+    /// filesystem. Without it mounts on read-only devices can't be remounted.
+    pub overlay: Option<PathBuf>,
+}
+
+impl Default for RemountConfig {
+    fn default() -> Self {
+        Self {
+            mounts: vec![PathBuf::from("/")],
+            overlay: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Seconds of wall-clock time before the session gets killed, it ends
+    /// with exit status 124 like under timeout(1).
+    pub timeout: Option<u64>,
+    /// Seconds of CPU time, RLIMIT_CPU.
+    pub cpu: Option<u64>,
+    /// Bytes of memory, `memory.max` with `shell.cgroup` and RLIMIT_AS
+    /// without.
+    pub memory: Option<u64>,
+    /// Processes, `pids.max` with `shell.cgroup` and RLIMIT_NPROC of the
+    /// whole user without.
+    pub processes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    #[default]
+    Stderr,
+    File,
+    Syslog,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Level of modules without an entry in `modules`.
+    pub level: String,
+    /// Levels by module path, like `"radbd::svc::sync" = "debug"`.
+    pub modules: BTreeMap<String, String>,
+    pub output: LogOutput,
+    /// Appended to when `output` is "file".
+    pub file: Option<PathBuf>,
+    /// Trace every message header going over the transport, the same as
+    /// setting `radbd::proto` to "trace".
+    pub protocol: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            modules: BTreeMap::new(),
+            output: LogOutput::Stderr,
+            file: None,
+            protocol: false,
+        }
+    }
+}
+
+impl LogConfig {
+    /// The filter `level` and `modules` make up, or `spec` in their place
+    /// when given one.
+    pub fn filter(&self, spec: Option<Filter>) -> Result<Filter> {
+        let mut ret = match spec {
+            Some(spec) => spec,
+            None => {
+                let mut ret = Filter::new(logger::parse_level(&self.level)?);
+                for (module, level) in &self.modules {
+                    ret = ret.module(module, logger::parse_level(level)?);
+                }
+                ret
+            },
+        };
+        if self.protocol {
+            ret = ret.module(logger::PROTOCOL_TARGET, LevelFilter::Trace);
+        }
+        Ok(ret)
+    }
+    pub fn output(&self) -> Output {
+        match (self.output, &self.file) {
+            (LogOutput::File, Some(file)) => Output::File(file.clone()),
+            (LogOutput::Syslog, _) => Output::Syslog,
+            _ => Output::Stderr,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivilegesConfig {
+    /// Who services run as, radbd's own credentials if unset.
+    pub user: Option<String>,
+    /// The user's primary group if unset.
+    pub group: Option<String>,
+    /// Supplementary groups.
+    pub groups: Vec<String>,
+    /// Whether `adb root` may switch back to radbd's own credentials, like
+    /// `ro.debuggable`.
+    pub allow_root: bool,
+    /// Start out as if `adb root` was run.
+    pub root: bool,
+}
+
+impl PrivilegesConfig {
+    pub fn credentials(&self) -> Result<Option<Credentials>> {
+        self.user.as_deref()
+            .map(|user| Credentials::lookup(user, self.group.as_deref(), &self.groups))
+            .transpose()
+    }
+    pub fn privileges(&self) -> Result<Privileges> {
+        Ok(Privileges::new(self.credentials()?, self.allow_root, self.root))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Where every OPEN gets recorded, nothing is if unset.
+    pub file: Option<PathBuf>,
+    /// Size in bytes the file is rotated at.
+    pub max_size: u64,
+    /// Rotated files to keep.
+    pub keep: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            max_size: 10 << 20,
+            keep: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// pcapng file every message gets recorded to, for Wireshark.
+    pub file: Option<PathBuf>,
+}
+
+impl Config {
+    /// Parses a config file, [`Config::validate`] has to follow once any
+    /// overrides are applied.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        toml::from_str(&data)
+            .with_context(|| format!("Invalid config {}", path.display()))
+    }
+    /// Checks what the TOML schema can't, errors name the offending key.
+    pub fn validate(&self) -> Result<()> {
+        if !self.usb.enabled && !self.tcp.enabled {
+            bail!("`usb.enabled`, `tcp.enabled`: no transport is enabled");
+        }
+        if self.usb.enabled && self.usb.setup.is_none() && self.usb.functionfs.is_none() {
+            bail!("`usb.functionfs`: needed unless `usb.setup` creates the gadget");
+        }
+        if self.usb.setup.is_some() && self.usb.gadget.is_some() {
+            bail!("`usb.gadget`: can't be used together with `usb.setup`");
+        }
+        for (idx, s) in self.usb.strings.iter().enumerate() {
+            if self.usb.strings[..idx].iter().any(|o| o.lang == s.lang) {
+                bail!("`usb.strings[{}].lang`: {:#x} is listed more than once", idx, s.lang);
+            }
+        }
+
+        if self.tcp.enabled {
+            let addr = self.tcp.addr();
+            if addr.rsplit_once(':').and_then(|(_, port)| port.parse::<u16>().ok()).is_none() {
+                bail!("`tcp.listen`: {:?} isn't a port or an address:port pair", self.tcp.listen);
+            }
+        }
+        match &self.tcp.state {
+            Some(state) if !state.is_absolute() => bail!("`tcp.state`: {:?} isn't an absolute path", state),
+            None if self.services.enabled.iter().any(|s| s == "tcpip") => {
+                bail!("`tcp.state`: needed for the tcpip service to remember the transport");
+            },
+            _ => (),
+        }
+
+        for (key, val) in &self.banner.properties {
+            if key.contains(['=', ';', '\0']) || val.contains([';', '\0']) {
+                bail!("`banner.properties.{}`: keys can't contain '=', ';' or NUL, values ';' or NUL", key);
+            }
+        }
+
+        for (idx, name) in self.services.enabled.iter().enumerate() {
+            if !SERVICES.contains(&name.as_str()) {
+                bail!("`services.enabled[{}]`: unknown service {:?}, known ones are {}", idx, name, SERVICES.join(", "));
+            }
+        }
+
+        if self.auth.policy == AuthMode::Keys && self.auth.keys.is_empty() && !self.auth.accept_new_keys {
+            bail!("`auth.keys`: policy \"keys\" without keys would lock every host out");
+        }
+
+        for (idx, root) in self.sync.roots.iter().enumerate() {
+            if !root.is_absolute() {
+                bail!("`sync.roots[{}]`: {:?} isn't an absolute path", idx, root);
+            }
+        }
+
+        if let Some(user) = &self.shell.user {
+            if nix::unistd::User::from_name(user)?.is_none() {
+                bail!("`shell.user`: no such user {:?}", user);
+            }
+        }
+        if let Some(rec) = &self.shell.recording {
+            if !rec.dir.is_absolute() {
+                bail!("`shell.recording.dir`: {:?} isn't an absolute path", rec.dir);
+            }
+        }
+        if let Some(cgroup) = &self.shell.cgroup {
+            if !cgroup.join("cgroup.procs").is_file() {
+                bail!("`shell.cgroup`: {:?} isn't a cgroup v2 directory", cgroup);
+            }
+        }
+
+        for name in self.limits.keys() {
+            if name != "shell" && name != "exec" {
+                bail!("`limits.{}`: only shell and exec run commands to limit", name);
+            }
+        }
+
+        if let Some(mode) = &self.framebuffer.mode {
+            Format::named(&mode.format, mode.width, mode.height)
+                .context("`framebuffer.mode.format`")?;
+        }
+
+        for (target, action) in &self.reboot.targets {
+            if *action == RebootAction::Command(Vec::new()) {
+                bail!("`reboot.targets.{}.command`: needs at least the program to run", target);
+            }
+        }
+
+        for (idx, mount) in self.remount.mounts.iter().enumerate() {
+            if !mount.is_absolute() {
+                bail!("`remount.mounts[{}]`: {:?} isn't an absolute path", idx, mount);
+            }
+        }
+        if let Some(overlay) = &self.remount.overlay {
+            if !overlay.is_absolute() {
+                bail!("`remount.overlay`: {:?} isn't an absolute path", overlay);
+            }
+        }
+
+        self.policy.validate()?;
+
+        if self.privileges.user.is_none() && (self.privileges.group.is_some() || !self.privileges.groups.is_empty()) {
+            bail!("`privileges.user`: needed for `privileges.group` and `privileges.groups`");
+        }
+        if self.privileges.root && !self.privileges.allow_root {
+            bail!("`privileges.root`: needs `privileges.allow_root`");
+        }
+        self.privileges.credentials()
+            .context("`privileges`")?;
+
+        logger::parse_level(&self.log.level)
+            .context("`log.level`")?;
+        for (module, level) in &self.log.modules {
+            logger::parse_level(level)
+                .with_context(|| format!("`log.modules.{}`", module))?;
+        }
+        if self.log.output == LogOutput::File && self.log.file.is_none() {
+            bail!("`log.file`: needed for output \"file\"");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_full_config() {
+        let cfg: Config = toml::from_str(r#"
+            [usb]
+            functionfs = "/dev/usb-ffs/adb"
+            aio = true
+            [[usb.strings]]
+            lang = 0x409
+            serial = "board-17"
+            [tcp]
+            enabled = true
+            listen = "127.0.0.1:5555"
+            state = "/var/lib/radbd/transport"
+            [banner.properties]
+            "ro.product.model" = "Board"
+            [services]
+            enabled = ["shell"]
+            [auth]
+            policy = "keys"
+            accept_new_keys = true
+            [sync]
+            roots = ["/data", "/tmp"]
+            [shell.recording]
+            dir = "/var/lib/radbd/casts"
+            keep = 10
+            [limits.exec]
+            timeout = 600
+            memory = 1_073_741_824
+            [reboot.targets]
+            "" = { restart = "" }
+            recovery = { command = ["/usr/sbin/enter-recovery", "--now"] }
+            off = "poweroff"
+            [remount]
+            mounts = ["/", "/opt"]
+            overlay = "/data/overlay"
+        "#).unwrap();
+        cfg.validate().unwrap();
+
+        assert!(cfg.usb.aio);
+        assert_eq!(cfg.usb.usb_strings().langs[0].serial.as_deref(), Some("board-17"));
+        assert_eq!(cfg.tcp.addr(), "127.0.0.1:5555");
+        assert_eq!(cfg.auth.policy, AuthMode::Keys);
+        assert_eq!(cfg.sync.roots.len(), 2);
+        assert_eq!(cfg.shell.recording.as_ref().map(|r| (r.keep, r.max_size)), Some((10, 16 << 20)));
+        assert_eq!(cfg.limits["exec"].memory, Some(1 << 30));
+        assert_eq!(cfg.reboot.targets["recovery"], RebootAction::Command(vec!["/usr/sbin/enter-recovery".to_string(), "--now".to_string()]));
+        assert_eq!(cfg.reboot.targets["off"], RebootAction::PowerOff);
+        assert!(!cfg.reboot.targets.contains_key("bootloader"));
+        assert_eq!(cfg.remount.mounts, [PathBuf::from("/"), PathBuf::from("/opt")]);
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let mut cfg = Config::default();
+        cfg.usb.functionfs = Some(PathBuf::from("/dev/usb-ffs/adb"));
+        cfg.validate().unwrap();
+
+        cfg.sync.roots.push(PathBuf::from("data"));
+        let err = cfg.validate().unwrap_err().to_string();
+        assert!(err.starts_with("`sync.roots[1]`"), "{}", err);
+
+        cfg.sync = SyncConfig::default();
+        cfg.services.enabled.push("telnet".to_string());
+        let err = cfg.validate().unwrap_err().to_string();
+        assert!(err.starts_with("`services.enabled[4]`"), "{}", err);
+    }
+}
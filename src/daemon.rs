@@ -0,0 +1,331 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Select, TryRecvError};
+use log::{error, info, trace, warn};
+use crate::auth::{self, AuthPolicy, PublicKey};
+use crate::capture::Capture;
+use crate::policy::{Denied, Peer};
+use crate::logger::PROTOCOL_TARGET;
+use crate::proto::{self, CommandType, Message};
+use crate::svc::{Registry, Stream, Waker};
+use crate::transport::{Transport, Writer};
+
+/// What woke the main loop up.
+enum Event {
+    Host,
+    Wake,
+    Output(u32),
+}
+
+/// Multiplexes the streams a host opens over one transport onto services.
+pub struct Daemon {
+    registry: Registry,
+    banner: String,
+    properties: BTreeMap<String, String>,
+    auth: AuthPolicy,
+    capture: Option<Arc<Capture>>,
+}
+
+impl Daemon {
+    pub fn new(registry: Registry) -> Self {
+        Self {
+            registry,
+            banner: "Rewrite it in Rust".to_string(),
+            properties: BTreeMap::new(),
+            auth: AuthPolicy::None,
+            capture: None,
+        }
+    }
+    pub fn registry_mut(&mut self) -> &mut Registry {
+        &mut self.registry
+    }
+    /// Properties like `ro.product.model` the host shows in `adb devices -l`.
+    pub fn set_properties(&mut self, properties: BTreeMap<String, String>) {
+        self.properties = properties;
+    }
+    pub fn set_auth(&mut self, auth: AuthPolicy) {
+        self.auth = auth;
+    }
+    /// Records the traffic of every connection from now on.
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(Arc::new(capture));
+    }
+    /// The identity sent in CNXN, `device:<serial>:<banner>` plus features.
+    ///
+    /// Properties, when set, take the place of the free form banner.
+    pub fn connect_banner(&self) -> Vec<u8> {
+        let mut fields: Vec<String> = if self.properties.is_empty() {
+            vec![self.banner.clone()]
+        } else {
+            self.properties.iter().map(|(key, val)| format!("{}={}", key, val)).collect()
+        };
+        let features = self.registry.features();
+        if !features.is_empty() {
+            fields.push(format!("features={}", features.join(",")));
+        }
+
+        let mut ret = format!("device:RIIR:{}", fields.join(";")).into_bytes();
+        ret.push(0);
+        ret
+    }
+    /// Serves a host until the transport goes away.
+    pub fn run(&self, transport: &mut dyn Transport) -> Result<()> {
+        let (mut reader, mut writer) = transport.split()?;
+        if let Some(capture) = &self.capture {
+            (reader, writer) = capture.wrap(reader, writer);
+        }
+        let (tx, rx) = crossbeam_channel::unbounded();
+
+        // Not scoped, a read blocked on a dead link mustn't keep run() from
+        // returning.
+        thread::spawn(move || {
+            loop {
+                let msg = proto::next_msg(&mut reader);
+                let failed = msg.is_err();
+                if tx.send(msg).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        // With auth on, CNXN only goes out once the host proved who it is
+        let announce = transport.announces() && !self.auth.required();
+        let mut peer = Peer::new(transport.kind());
+        let banner = self.connect_banner();
+        if announce {
+            Message::connect(proto::ADB_VERSION, proto::MAXDATA, &banner).send_to(&mut writer)?;
+        }
+        loop {
+            let msg = match rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(msg) => msg?,
+                Err(RecvTimeoutError::Timeout) => {
+                    if announce {
+                        Message::connect(proto::ADB_VERSION, proto::MAXDATA, &banner).send_to(&mut writer)?;
+                    }
+                    continue;
+                },
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            if let CommandType::Connect{..} = msg.meta().cmd() {
+                if self.auth.required() {
+                    let Some((key, known)) = self.authenticate(&rx, &mut writer)? else {
+                        return Ok(());
+                    };
+                    info!("Host authenticated with key {} {}", key.fingerprint(), key.comment());
+                    peer.key = Some(key);
+                    peer.known_key = known;
+                }
+                if !announce {
+                    Message::connect(proto::ADB_VERSION, proto::MAXDATA, &banner).send_to(&mut writer)?;
+                }
+                break;
+            }
+        }
+
+        info!("Connected");
+        let mut conn = Connection {
+            registry: &self.registry,
+            peer,
+            writer,
+            streams: HashMap::new(),
+            next_id: 3,
+            wake: crossbeam_channel::unbounded(),
+        };
+        let ret = conn.serve(&rx);
+
+        for (_, stream) in conn.streams.drain() {
+            if let Err(e) = stream.close() {
+                warn!("Failed to close a stream: {:?}", e);
+            }
+        }
+        ret
+    }
+    /// Runs the AUTH exchange following the host's CNXN, `None` if the host
+    /// went away before getting in. The flag tells whether the key was a
+    /// configured one.
+    fn authenticate(&self, rx: &Receiver<Result<Message>>, writer: &mut Writer) -> Result<Option<(PublicKey, bool)>> {
+        let AuthPolicy::Keys{keys, accept_new} = &self.auth else { return Ok(None); };
+
+        let mut token = auth::new_token()?;
+        Message::auth(auth::ADB_AUTH_TOKEN, token.to_vec()).send_to(writer)?;
+        loop {
+            let msg = match rx.recv() {
+                Ok(Ok(msg)) => msg,
+                Ok(Err(e)) => {
+                    info!("Host went away before authenticating: {:#}", e);
+                    return Ok(None);
+                },
+                Err(_) => return Ok(None),
+            };
+            let ty = match msg.meta().cmd() {
+                CommandType::Auth{ty, ..} => *ty,
+                CommandType::Connect{..} => {
+                    // The host started over
+                    token = auth::new_token()?;
+                    Message::auth(auth::ADB_AUTH_TOKEN, token.to_vec()).send_to(writer)?;
+                    continue;
+                },
+                other => {
+                    warn!("Ignoring {:?} before authentication", other);
+                    continue;
+                },
+            };
+
+            match ty {
+                auth::ADB_AUTH_SIGNATURE => {
+                    if let Some(key) = keys.iter().find(|k| k.verify(&token, msg.data())) {
+                        return Ok(Some((key.clone(), true)));
+                    }
+                    // The host tries its next key against a fresh token
+                    token = auth::new_token()?;
+                    Message::auth(auth::ADB_AUTH_TOKEN, token.to_vec()).send_to(writer)?;
+                },
+                auth::ADB_AUTH_RSAPUBLICKEY => {
+                    let key = match PublicKey::parse(&String::from_utf8_lossy(msg.data())) {
+                        Ok(key) => key,
+                        Err(e) => {
+                            warn!("Host offered an invalid key: {:#}", e);
+                            continue;
+                        },
+                    };
+                    if *accept_new {
+                        info!("Accepting new host key {} {}", key.fingerprint(), key.comment());
+                        return Ok(Some((key, false)));
+                    }
+                    warn!("Refusing unknown host key {} {}", key.fingerprint(), key.comment());
+                },
+                other => warn!("Ignoring AUTH of type {}", other),
+            }
+        }
+    }
+}
+
+struct Connection<'a> {
+    registry: &'a Registry,
+    peer: Peer,
+    writer: Writer,
+    streams: HashMap<u32, Stream>,
+    next_id: u32,
+    wake: (crossbeam_channel::Sender<u32>, crossbeam_channel::Receiver<u32>),
+}
+
+impl Connection<'_> {
+    fn serve(&mut self, rx: &crossbeam_channel::Receiver<Result<Message>>) -> Result<()> {
+        loop {
+            let event = {
+                let mut sel = Select::new();
+                sel.recv(rx);
+                sel.recv(&self.wake.1);
+
+                let mut ids = Vec::new();
+                for (id, stream) in self.streams.iter_mut() {
+                    if let Some(recv) = stream.wants_output() {
+                        sel.recv(recv);
+                        ids.push(*id);
+                    }
+                }
+
+                match sel.ready() {
+                    0 => Event::Host,
+                    1 => Event::Wake,
+                    n => Event::Output(ids[n - 2]),
+                }
+            };
+
+            let id = match event {
+                Event::Host => {
+                    let msg = match rx.try_recv() {
+                        Ok(Ok(msg)) => msg,
+                        Ok(Err(e)) => {
+                            info!("Connection to the host lost: {:#}", e);
+                            return Ok(());
+                        },
+                        Err(TryRecvError::Empty) => continue,
+                        Err(TryRecvError::Disconnected) => return Ok(()),
+                    };
+                    match self.handle_msg(msg)? {
+                        Some(id) => id,
+                        None => continue,
+                    }
+                },
+                Event::Wake => {
+                    let Ok(id) = self.wake.1.try_recv() else { continue; };
+                    id
+                },
+                Event::Output(id) => id,
+            };
+
+            let Some(stream) = self.streams.get_mut(&id) else { continue; };
+            let closed = stream.tick(&mut self.writer)
+                .context("Failed to tick a stream")?;
+            if closed {
+                self.streams.remove(&id);
+            }
+        }
+    }
+    /// Returns the stream that needs a tick afterwards.
+    fn handle_msg(&mut self, msg: Message) -> Result<Option<u32>> {
+        trace!(target: PROTOCOL_TARGET, "rx: {:x?}", msg.meta());
+        match msg.meta().cmd() {
+            CommandType::Open{local_id, ..} => {
+                let name = String::from_utf8_lossy(msg.data());
+                let name = name.trim_end_matches('\0');
+                let id = self.next_id;
+                let waker = Waker::new(id, self.wake.0.clone());
+                let stream = match self.registry.spawn(id, *local_id, name, &self.peer, waker) {
+                    Ok(Some(stream)) => stream,
+                    Ok(None) => {
+                        warn!("Refusing unknown service {:?}", name);
+                        Message::close(0, *local_id).send_to(&mut self.writer)?;
+                        return Ok(None);
+                    },
+                    Err(e) if e.is::<Denied>() => {
+                        warn!("Denied {:?} to {} host {}: {}", name, self.peer.transport,
+                              self.peer.fingerprint().unwrap_or("without a key"), e);
+                        Message::close(0, *local_id).send_to(&mut self.writer)?;
+                        return Ok(None);
+                    },
+                    Err(e) => {
+                        error!("Failed to start {:?}: {:?}", name, e);
+                        Message::close(0, *local_id).send_to(&mut self.writer)?;
+                        return Ok(None);
+                    },
+                };
+                self.streams.insert(id, stream);
+                self.next_id += 1;
+                Ok(Some(id))
+            }
+            CommandType::Ready{remote_id, ..} | CommandType::Write{remote_id, ..} => {
+                let id = *remote_id;
+                // The stream might have closed while this was in flight
+                let Some(stream) = self.streams.get_mut(&id) else { return Ok(None); };
+                if let Err(e) = stream.handle_msg(msg) {
+                    warn!("Stream {} failed: {:?}", id, e);
+                    let stream = self.streams.remove(&id).unwrap();
+                    let remote_id = stream.remote_id();
+                    if let Err(e) = stream.close() {
+                        warn!("Failed to close stream {}: {:?}", id, e);
+                    }
+                    Message::close(id, remote_id).send_to(&mut self.writer)?;
+                    return Ok(None);
+                }
+                Ok(Some(id))
+            }
+            CommandType::Close{remote_id, ..} => {
+                if let Some(stream) = self.streams.remove(remote_id) {
+                    if let Err(e) = stream.close() {
+                        warn!("Failed to close stream {}: {:?}", remote_id, e);
+                    }
+                }
+                Ok(None)
+            }
+            other => {
+                warn!("Ignoring unexpected {:?}", other);
+                Ok(None)
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use nix::mount::{mount, umount, MsFlags};
+use crate::usb::UsbStrings;
+
+/// Names the gadget a restarting radbd left in place for the next one to
+/// take over, see [`Gadget::keep`].
+pub const KEPT_ENV: &str = "RADBD_KEPT_GADGET";
+
+/// Writes device level strings into an existing configfs gadget directory.
+///
+/// This has to happen before the gadget is bound to an UDC, the kernel
+/// refuses changes to strings of a bound gadget.
+pub fn write_strings(gadget: &Path, strings: &UsbStrings) -> Result<()> {
+    for lang in &strings.langs {
+        let dir = gadget.join("strings").join(format!("{:#x}", lang.code));
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {:?}", dir))?;
+
+        let entries = [
+            ("serialnumber", &lang.serial),
+            ("manufacturer", &lang.manufacturer),
+            ("product", &lang.product),
+        ];
+        for (name, value) in entries {
+            if let Some(value) = value {
+                write_attr(&dir, name, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_attr(dir: &Path, name: &str, value: &str) -> Result<()> {
+    let path = dir.join(name);
+    fs::write(&path, value)
+        .with_context(|| format!("Failed to write {:?}", path))
+}
+
+#[derive(Debug, Clone)]
+pub struct GadgetConfig {
+    /// Where configfs is mounted.
+    pub configfs: PathBuf,
+    /// Where the kernel lists available UDCs.
+    pub udc_class: PathBuf,
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// UDC to bind to, the first one found is used if unset.
+    pub udc: Option<String>,
+    /// FunctionFS instance name, `functions/ffs.<instance>` in configfs.
+    pub instance: String,
+    /// Where functionfs gets mounted.
+    pub ffs_mount: PathBuf,
+    /// Whether radbd mounts functionfs itself or it is already mounted.
+    pub mount: bool,
+    /// Whether to answer Microsoft OS descriptor requests, needed for WinUSB
+    /// to pick up the adb interface without a driver.
+    pub os_desc: bool,
+}
+
+impl GadgetConfig {
+    /// The gadget's directory in configfs.
+    pub fn dir(&self) -> PathBuf {
+        self.configfs.join("usb_gadget").join(&self.name)
+    }
+}
+
+impl Default for GadgetConfig {
+    fn default() -> Self {
+        Self {
+            configfs: PathBuf::from("/sys/kernel/config"),
+            udc_class: PathBuf::from("/sys/class/udc"),
+            name: "radbd".to_string(),
+            vendor_id: 0x18d1,
+            product_id: 0x4ee7,
+            udc: None,
+            instance: "adb".to_string(),
+            ffs_mount: PathBuf::from("/dev/usb-ffs/adb"),
+            mount: true,
+            os_desc: true,
+        }
+    }
+}
+
+/// A configfs gadget created by radbd, torn down again on drop.
+#[derive(Debug)]
+pub struct Gadget {
+    cfg: GadgetConfig,
+    dir: PathBuf,
+    strings: UsbStrings,
+    mounted: bool,
+    bound: bool,
+}
+
+impl Gadget {
+    pub fn create(cfg: GadgetConfig, strings: &UsbStrings) -> Result<Self> {
+        let dir = cfg.dir();
+        if dir.exists() {
+            bail!("Gadget {:?} already exists", dir);
+        }
+
+        let mut ret = Self {
+            cfg,
+            dir,
+            strings: strings.clone(),
+            mounted: false,
+            bound: false,
+        };
+        // Dropping ret on error tears down whatever got created so far
+        ret.populate()?;
+        Ok(ret)
+    }
+    /// Takes over a gadget an earlier radbd kept with [`Gadget::keep`].
+    pub fn adopt(cfg: GadgetConfig, strings: &UsbStrings) -> Result<Self> {
+        let dir = cfg.dir();
+        if !dir.exists() {
+            bail!("Gadget {:?} to take over is gone", dir);
+        }
+        // The kernel unbinds it once the old endpoints are closed, but
+        // doesn't have to have gotten to that yet
+        let udc = fs::read_to_string(dir.join("UDC")).unwrap_or_default();
+        if !udc.trim().is_empty() {
+            write_attr(&dir, "UDC", "")?;
+        }
+        info!("Taking over gadget {}", cfg.name);
+        Ok(Self {
+            mounted: cfg.mount,
+            cfg,
+            dir,
+            strings: strings.clone(),
+            bound: false,
+        })
+    }
+    /// Leaves the gadget in place for the radbd that replaces this one,
+    /// which can't unmount functionfs while its endpoints are still open.
+    /// Returns the directory for [`KEPT_ENV`].
+    pub fn keep(self) -> PathBuf {
+        let dir = self.dir.clone();
+        // Skips the teardown, this process is on its way out anyway
+        std::mem::forget(self);
+        dir
+    }
+    fn populate(&mut self) -> Result<()> {
+        let dir = &self.dir;
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {:?}", dir))?;
+
+        write_attr(dir, "idVendor", &format!("{:#06x}", self.cfg.vendor_id))?;
+        write_attr(dir, "idProduct", &format!("{:#06x}", self.cfg.product_id))?;
+        write_attr(dir, "bcdUSB", "0x0200")?;
+        write_strings(dir, &self.strings)?;
+
+        let config = self.config_dir();
+        for lang in &self.strings.langs {
+            let strings = config.join("strings").join(format!("{:#x}", lang.code));
+            fs::create_dir_all(&strings)
+                .with_context(|| format!("Failed to create {:?}", strings))?;
+            write_attr(&strings, "configuration", "adb")?;
+        }
+        write_attr(&config, "MaxPower", "500")?;
+
+        let function = self.function_dir();
+        fs::create_dir_all(&function)
+            .with_context(|| format!("Failed to create {:?}", function))?;
+        symlink(&function, self.function_link())
+            .context("Failed to add the function to the configuration")?;
+
+        if self.cfg.os_desc {
+            let os_desc = self.dir.join("os_desc");
+            fs::create_dir_all(&os_desc)
+                .with_context(|| format!("Failed to create {:?}", os_desc))?;
+            write_attr(&os_desc, "use", "1")?;
+            write_attr(&os_desc, "b_vendor_code", "0x1")?;
+            write_attr(&os_desc, "qw_sign", "MSFT100")?;
+            symlink(&config, self.os_desc_link())
+                .context("Failed to add the configuration to os_desc")?;
+        }
+
+        if self.cfg.mount {
+            fs::create_dir_all(&self.cfg.ffs_mount)
+                .with_context(|| format!("Failed to create {:?}", self.cfg.ffs_mount))?;
+            mount(Some(self.cfg.instance.as_str()), &self.cfg.ffs_mount,
+                  Some("functionfs"), MsFlags::empty(), None::<&str>)
+                .with_context(|| format!("Failed to mount functionfs at {:?}", self.cfg.ffs_mount))?;
+            self.mounted = true;
+        }
+
+        Ok(())
+    }
+    fn config_dir(&self) -> PathBuf {
+        self.dir.join("configs").join("c.1")
+    }
+    fn function_dir(&self) -> PathBuf {
+        self.dir.join("functions").join(format!("ffs.{}", self.cfg.instance))
+    }
+    fn os_desc_link(&self) -> PathBuf {
+        self.dir.join("os_desc").join("c.1")
+    }
+    fn function_link(&self) -> PathBuf {
+        self.config_dir().join(format!("ffs.{}", self.cfg.instance))
+    }
+    pub fn functionfs(&self) -> &Path {
+        &self.cfg.ffs_mount
+    }
+    fn pick_udc(&self) -> Result<String> {
+        if let Some(udc) = &self.cfg.udc {
+            return Ok(udc.clone());
+        }
+
+        let mut udcs = fs::read_dir(&self.cfg.udc_class)
+            .with_context(|| format!("Failed to list UDCs in {:?}", self.cfg.udc_class))?
+            .map(|e| Ok(e?.file_name().to_string_lossy().to_string()))
+            .collect::<Result<Vec<String>>>()?;
+        udcs.sort();
+        udcs.into_iter().next()
+            .context("No UDC available")
+    }
+    /// Binds the gadget, FunctionFS descriptors have to be written to ep0 first.
+    pub fn bind(&mut self) -> Result<()> {
+        let udc = self.pick_udc()?;
+        write_attr(&self.dir, "UDC", &udc)
+            .with_context(|| format!("Failed to bind to {}", udc))?;
+        self.bound = true;
+        info!("Bound gadget {} to {}", self.cfg.name, udc);
+        Ok(())
+    }
+    fn teardown(&mut self) {
+        if self.bound {
+            if let Err(e) = write_attr(&self.dir, "UDC", "") {
+                warn!("Failed to unbind gadget: {:?}", e);
+            }
+            self.bound = false;
+        }
+
+        if self.mounted {
+            if let Err(e) = umount(&self.cfg.ffs_mount) {
+                warn!("Failed to unmount {:?}: {}", self.cfg.ffs_mount, e);
+            }
+            self.mounted = false;
+        }
+
+        // configfs removes attribute files together with their directory
+        let _ = fs::remove_file(self.os_desc_link());
+        let _ = fs::remove_file(self.function_link());
+        let mut dirs = vec![];
+        for lang in &self.strings.langs {
+            dirs.push(self.config_dir().join("strings").join(format!("{:#x}", lang.code)));
+        }
+        dirs.push(self.config_dir());
+        dirs.push(self.function_dir());
+        for lang in &self.strings.langs {
+            dirs.push(self.dir.join("strings").join(format!("{:#x}", lang.code)));
+        }
+        dirs.push(self.dir.clone());
+
+        for dir in dirs {
+            if let Err(e) = fs::remove_dir(&dir) {
+                warn!("Failed to remove {:?}: {}", dir, e);
+            }
+        }
+    }
+}
+
+impl Drop for Gadget {
+    fn drop(&mut self) {
+        self.teardown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn mock_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("radbd-gadget-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("udc").join("dummy_udc.1")).unwrap();
+        fs::create_dir_all(root.join("udc").join("dummy_udc.0")).unwrap();
+        root
+    }
+
+    fn mock_config(root: &Path) -> GadgetConfig {
+        GadgetConfig {
+            configfs: root.join("configfs"),
+            udc_class: root.join("udc"),
+            ffs_mount: root.join("ffs"),
+            mount: false,
+            ..GadgetConfig::default()
+        }
+    }
+
+    #[test]
+    fn creates_and_binds_to_first_udc() {
+        let root = mock_root("bind");
+        let mut strings = UsbStrings::default();
+        strings.langs[0].serial = Some("board-17".to_string());
+
+        let mut gadget = Gadget::create(mock_config(&root), &strings).unwrap();
+        let dir = root.join("configfs/usb_gadget/radbd");
+        assert_eq!(fs::read_to_string(dir.join("idVendor")).unwrap(), "0x18d1");
+        assert_eq!(fs::read_to_string(dir.join("strings/0x409/serialnumber")).unwrap(), "board-17");
+        assert_eq!(fs::read_link(dir.join("configs/c.1/ffs.adb")).unwrap(), dir.join("functions/ffs.adb"));
+        assert_eq!(fs::read_link(dir.join("os_desc/c.1")).unwrap(), dir.join("configs/c.1"));
+
+        gadget.bind().unwrap();
+        assert_eq!(fs::read_to_string(dir.join("UDC")).unwrap(), "dummy_udc.0");
+
+        drop(gadget);
+        assert_eq!(fs::read_to_string(dir.join("UDC")).unwrap(), "");
+        assert!(!dir.join("configs/c.1/ffs.adb").exists());
+        assert!(!dir.join("os_desc/c.1").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn refuses_existing_gadget() {
+        let root = mock_root("exists");
+        let cfg = mock_config(&root);
+        fs::create_dir_all(cfg.configfs.join("usb_gadget/radbd")).unwrap();
+
+        assert!(Gadget::create(cfg, &UsbStrings::default()).is_err());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_gadget_across_a_restart() {
+        let root = mock_root("restart");
+        let cfg = mock_config(&root);
+        let dir = cfg.dir();
+        let mut gadget = Gadget::create(cfg.clone(), &UsbStrings::default()).unwrap();
+        gadget.bind().unwrap();
+
+        assert_eq!(gadget.keep(), dir);
+        assert_eq!(fs::read_link(dir.join("configs/c.1/ffs.adb")).unwrap(), dir.join("functions/ffs.adb"));
+        assert!(Gadget::create(cfg.clone(), &UsbStrings::default()).is_err());
+
+        let mut gadget = Gadget::adopt(cfg.clone(), &UsbStrings::default()).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("UDC")).unwrap(), "");
+        gadget.bind().unwrap();
+        assert_eq!(fs::read_to_string(dir.join("UDC")).unwrap(), "dummy_udc.0");
+
+        drop(gadget);
+        assert!(!dir.join("configs/c.1/ffs.adb").exists());
+        let other = GadgetConfig { name: "other".to_string(), ..cfg };
+        assert!(Gadget::adopt(other, &UsbStrings::default()).is_err());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,24 @@
+//! The device side of the adb protocol.
+//!
+//! [`Daemon`] serves a host over a [`transport::Transport`], handing the
+//! streams it opens to services looked up in a [`svc::Registry`]. Embedders
+//! can register their own [`svc::Service`] implementations next to the
+//! built-in ones.
+
+pub mod proto;
+pub mod usb;
+pub mod aio;
+pub mod gadget;
+pub mod svc;
+pub mod transport;
+pub mod config;
+pub mod auth;
+pub mod logger;
+pub mod capture;
+pub mod policy;
+pub mod privileges;
+pub mod audit;
+pub mod cgroup;
+mod daemon;
+
+pub use daemon::Daemon;
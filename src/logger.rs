@@ -0,0 +1,194 @@
+//! A [`log`] backend for radbd, filtering by level per module and writing to
+//! stderr, a file or syslog.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Target of the per message tx/rx trace.
+pub const PROTOCOL_TARGET: &str = "radbd::proto";
+
+/// Which level applies to which module.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    pub fn new(default: LevelFilter) -> Self {
+        Self {
+            default,
+            modules: Vec::new(),
+        }
+    }
+    /// Sets the level for `module` and everything below it.
+    pub fn module(mut self, module: &str, level: LevelFilter) -> Self {
+        self.modules.retain(|(m, _)| m != module);
+        self.modules.push((module.to_string(), level));
+        self
+    }
+    /// Parses `RUST_LOG` style specs, `info,radbd::svc::sync=debug`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut ret = Self::new(LevelFilter::Info);
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some((module, level)) => ret = ret.module(module, parse_level(level)?),
+                None => ret.default = parse_level(part)?,
+            }
+        }
+        Ok(ret)
+    }
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules.iter()
+            .filter(|(m, _)| target == m || target.strip_prefix(m.as_str()).is_some_and(|r| r.starts_with("::")))
+            .max_by_key(|(m, _)| m.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+    fn max(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+}
+
+pub fn parse_level(level: &str) -> Result<LevelFilter> {
+    LevelFilter::from_str(level)
+        .with_context(|| format!("Invalid log level {:?}, expected off, error, warn, info, debug or trace", level))
+}
+
+#[derive(Debug, Clone)]
+pub enum Output {
+    Stderr,
+    File(PathBuf),
+    /// The local syslog daemon through `/dev/log`.
+    Syslog,
+}
+
+enum Sink {
+    Stderr,
+    File(File),
+    Syslog(UnixDatagram),
+}
+
+struct Logger {
+    filter: Filter,
+    sink: Mutex<Sink>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, meta: &Metadata) -> bool {
+        meta.level() <= self.filter.level_for(meta.target())
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Nothing sensible to do when logging itself fails
+        let mut sink = self.sink.lock().unwrap();
+        let _ = match &mut *sink {
+            Sink::Stderr => writeln!(std::io::stderr(), "{}", format_line(record)),
+            Sink::File(file) => writeln!(file, "{}", format_line(record)),
+            Sink::Syslog(sock) => {
+                let msg = format!("<{}>radbd[{}]: {}: {}", syslog_priority(record.level()),
+                                  process::id(), record.target(), record.args());
+                sock.send(msg.as_bytes()).map(|_| ())
+            },
+        };
+    }
+    fn flush(&self) {
+        if let Sink::File(file) = &mut *self.sink.lock().unwrap() {
+            let _ = file.flush();
+        }
+    }
+}
+
+fn format_line(record: &Record) -> String {
+    format!("{} {:5} {}: {}", timestamp(SystemTime::now()), record.level(), record.target(), record.args())
+}
+
+/// UTC, `2024-05-01T12:00:00.000Z`.
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day,
+            rem / 3600, rem / 60 % 60, rem % 60, since.subsec_millis())
+}
+
+fn syslog_priority(level: Level) -> u8 {
+    const LOG_DAEMON: u8 = 3 << 3;
+    LOG_DAEMON + match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Installs the logger, once per process.
+pub fn init(filter: Filter, output: &Output) -> Result<()> {
+    let sink = match output {
+        Output::Stderr => Sink::Stderr,
+        Output::File(path) => Sink::File(OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Failed to open log file {:?}", path))?),
+        Output::Syslog => {
+            let sock = UnixDatagram::unbound()?;
+            sock.connect("/dev/log")
+                .context("Failed to connect to syslog at /dev/log")?;
+            Sink::Syslog(sock)
+        },
+    };
+
+    let max = filter.max();
+    if log::set_boxed_logger(Box::new(Logger { filter, sink: Mutex::new(sink) })).is_err() {
+        bail!("A logger is already installed");
+    }
+    log::set_max_level(max);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn longest_module_prefix_wins() {
+        let filter = Filter::parse("warn,radbd::svc=debug,radbd::svc::sync=trace,radbd::proto=off").unwrap();
+        assert_eq!(filter.level_for("radbd::daemon"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("radbd::svc"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("radbd::svc::shell"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("radbd::svc::sync"), LevelFilter::Trace);
+        assert_eq!(filter.level_for("radbd::svcx"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("radbd::proto"), LevelFilter::Off);
+        assert_eq!(filter.max(), LevelFilter::Trace);
+
+        assert!(Filter::parse("radbd=loud").is_err());
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(timestamp(time), "2024-02-29T12:34:56.789Z");
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+}
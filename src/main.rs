@@ -1,132 +1,223 @@
-mod proto;
-mod usb;
-mod svc;
-
 use std::{
-    fs::OpenOptions,
-    io::Write,
     path::PathBuf,
     env,
-    collections::HashMap,
     thread,
-    time::Duration,
-    sync::atomic::{AtomicBool, Ordering},
+    process,
+    sync::{Arc, Mutex},
 };
-use anyhow::{Context, Result};
-use proto::{CommandType, Message};
-use svc::Stream;
-use crossbeam_channel::select;
+use nix::sys::signal::{SigSet, Signal};
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use radbd::{gadget, logger, svc, usb, Daemon};
+use radbd::svc::tcpip;
+use radbd::audit::AuditLog;
+use radbd::auth::AuthPolicy;
+use radbd::capture::Capture;
+use radbd::config::{AuthMode, Config, LogOutput};
+use radbd::transport::TcpServer;
+use radbd::usb::LANG_EN_US;
 
-fn main() -> Result<()> {
-    let endpoint_path = PathBuf::from(env::args().skip(1).next()
-        .expect("First argument has to be functionfs path"));
-
-    let mut ep_control = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(false)
-        .open(endpoint_path.join("ep0"))
-        .context("Failed to open ep0")?;
-
-    ep_control.write_all(usb::ADB_DESCRIPTOR_V2.as_bytes())?;
-    ep_control.write_all(usb::ADB_STRINGS.as_bytes())?;
-
-    let mut ep_out = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .create(false)
-        .open(endpoint_path.join("ep1"))
-        .context("Failed to open ep1")?;
-
-    let mut ep_in = OpenOptions::new()
-        .read(false)
-        .write(true)
-        .create(false)
-        .open(endpoint_path.join("ep2"))
-        .context("Failed to open ep2")?;
-
-    let connected = AtomicBool::new(false);
-    thread::scope(|s| {
-        s.spawn(|| {
-            while !connected.load(Ordering::Acquire) {
-                Message::connect(proto::ADB_VERSION, proto::MAXDATA, b"device:RIIR:Rewrite it in Rust\0").send_to(&mut ep_in)
-                    .expect("Failed to send connect message");
-                thread::sleep(Duration::from_secs(1));
-            }
-        });
+fn parse_hex16(val: &str) -> Result<u16> {
+    u16::from_str_radix(val.trim_start_matches("0x"), 16)
+        .with_context(|| format!("Invalid hex value {:?}", val))
+}
+
+fn parse_lang(code: &str) -> Result<u16> {
+    let ret = match code.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => code.parse(),
+    };
+    ret.with_context(|| format!("Invalid language code {:?}", code))
+}
 
-        loop {
-            let d = proto::next_msg(&mut ep_out).expect("Failed to read next message");
-            match d.meta().cmd() {
-                CommandType::Connect{..} => break,
-                _ => continue,
+/// The settings, and the log filter `--log` replaces `log.level` and
+/// `log.modules` with.
+fn parse_args() -> Result<(Config, Option<logger::Filter>)> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config_path = match args.iter().position(|arg| arg == "--config") {
+        Some(idx) => Some(PathBuf::from(args.get(idx + 1).context("--config requires a value")?)),
+        None => None,
+    };
+    let mut cfg = match &config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    // What `adb tcpip` and `adb usb` chose beats the config file, options
+    // given right here beat both
+    if let Some(state) = &cfg.tcp.state {
+        if let Some(mode) = tcpip::Mode::load(state)? {
+            mode.apply(&mut cfg);
+        }
+    }
+
+    let mut lang = LANG_EN_US;
+    let mut setup = cfg.usb.setup.is_some();
+    let mut setup_cfg = cfg.usb.setup.clone().unwrap_or_default();
+    let mut endpoint_path = None;
+    let mut tcp = false;
+    let mut log = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next()
+            .with_context(|| format!("{} requires a value", arg));
+
+        match arg.as_str() {
+            "--config" => { value()?; },
+            "--gadget" => cfg.usb.gadget = Some(PathBuf::from(value()?)),
+            "--lang" => lang = parse_lang(&value()?)?,
+            "--serial" => cfg.usb.strings_mut(lang).serial = Some(value()?),
+            "--manufacturer" => cfg.usb.strings_mut(lang).manufacturer = Some(value()?),
+            "--product" => cfg.usb.strings_mut(lang).product = Some(value()?),
+            "--interface" => cfg.usb.strings_mut(lang).interface = Some(value()?),
+            "--setup" => setup = true,
+            "--configfs" => setup_cfg.configfs = Some(PathBuf::from(value()?)),
+            "--udc" => setup_cfg.udc = Some(value()?),
+            "--vid" => setup_cfg.vendor_id = Some(parse_hex16(&value()?)?),
+            "--pid" => setup_cfg.product_id = Some(parse_hex16(&value()?)?),
+            "--no-mount" => setup_cfg.mount = false,
+            "--instance" => setup_cfg.instance = value()?,
+            "--no-os-desc" => cfg.usb.os_descriptors = false,
+            "--aio" => cfg.usb.aio = true,
+            "--tcp" => {
+                cfg.tcp.listen = value()?;
+                cfg.tcp.enabled = true;
+                tcp = true;
+            },
+            "--log" => log = Some(logger::Filter::parse(&value()?)?),
+            "--log-file" => {
+                cfg.log.file = Some(PathBuf::from(value()?));
+                cfg.log.output = LogOutput::File;
+            },
+            "--syslog" => cfg.log.output = LogOutput::Syslog,
+            "--trace-protocol" => cfg.log.protocol = true,
+            "--capture" => cfg.capture.file = Some(PathBuf::from(value()?)),
+            "--audit" => cfg.audit.file = Some(PathBuf::from(value()?)),
+            other if other.starts_with("--") => bail!("Unknown option {}", other),
+            other => {
+                if endpoint_path.is_some() {
+                    bail!("Unexpected argument {}", other);
+                }
+                endpoint_path = Some(PathBuf::from(other));
             }
         }
+    }
+
+    cfg.usb.setup = setup.then_some(setup_cfg);
+    if let Some(path) = endpoint_path {
+        cfg.usb.functionfs = Some(path);
+        cfg.usb.enabled = true;
+    } else if tcp && !setup && config_path.is_none() {
+        // `radbd --tcp PORT` serves TCP only, like it always did
+        cfg.usb.enabled = false;
+    }
+
+    cfg.validate()
+        .context("Invalid settings")?;
+    Ok((cfg, log))
+}
+
+/// Tears the gadget down, if there is one by then, when radbd gets asked to
+/// quit.
+///
+/// Has to come before any other thread gets spawned, those inherit the
+/// blocked signals and would die of them otherwise.
+fn teardown_on_signal(gadget: Arc<Mutex<Option<gadget::Gadget>>>) -> Result<()> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGHUP);
+    signals.thread_block()?;
 
-        connected.store(true, Ordering::Release);
+    thread::spawn(move || {
+        let sig = signals.wait();
+        if let Some(gadget) = gadget.lock().unwrap().take() {
+            info!("Got {:?}, tearing down the gadget", sig);
+            drop(gadget);
+        }
+        process::exit(0);
     });
+    Ok(())
+}
 
-    println!("Connected!");
-    let mut streams: HashMap<u32, Stream> = HashMap::new();
-    let mut next_id = 3;
-    let (tx, rx) = crossbeam_channel::unbounded();
-
-    thread::scope(|s| {
-        s.spawn(|| {
-            loop {
-                let msg = proto::next_msg(&mut ep_out)
-                    .expect("Failed to read next msg");
-                if tx.send(msg).is_err() {
-                    break;
-                };
+fn serve_tcp(daemon: Arc<Daemon>, server: TcpServer) -> Result<()> {
+    loop {
+        let mut transport = server.accept()?;
+        info!("Host connected from {}", transport.peer());
+        let daemon = daemon.clone();
+        thread::spawn(move || {
+            if let Err(e) = daemon.run(&mut transport) {
+                error!("Connection failed: {:?}", e);
             }
         });
+    }
+}
 
-        loop {
-            select!(
-                recv(rx) -> msg => {
-                    let msg = msg.unwrap();
-                    println!("rx: {:#x?}", msg.meta());
-                    match msg.meta().cmd() {
-                        CommandType::Open{local_id, ..} => {
-                            let name = String::from_utf8_lossy(msg.data());
-                            let stream = svc::spawn(next_id, *local_id, name.to_string())
-                                .expect("Failed to spawn a service");
-                            streams.insert(next_id, stream);
-                            next_id += 1;
-                        }
-                        CommandType::Ready{remote_id, ..} | CommandType::Write{remote_id, ..} => {
-                            let stream = streams.get_mut(&remote_id).unwrap();
-                            stream.handle_msg(msg).expect("Failed to handle a message");
-                        }
-                        CommandType::Close{remote_id, ..} => {
-                            streams.remove(&remote_id).unwrap();
-                        }
-                        other => {
-                            todo!("{:?}", other);
-                        }
-                    }
-
-                    for (_, stream) in streams.iter_mut() {
-                        let kill_it = stream.tick(&mut ep_in)
-                            .expect("Failed to tick a stream");
-                        if kill_it {
-                            stream.schedule_death();
-                        }
-                    }
-                },
-                default(Duration::from_millis(100)) => {
-                    for (_, stream) in streams.iter_mut() {
-                        let kill_it = stream.tick(&mut ep_in)
-                            .expect("Failed to tick a stream");
-                        if kill_it {
-                            stream.schedule_death();
-                        }
-                    }
-                },
-            );
-        }
-    });
+fn main() -> Result<()> {
+    let (cfg, log) = parse_args()?;
+    logger::init(cfg.log.filter(log)?, &cfg.log.output())?;
+    let gadget = Arc::new(Mutex::new(None));
+    teardown_on_signal(gadget.clone())?;
 
-    Ok(())
+    let mut registry = svc::Registry::builtin(&cfg)?;
+    if let Some(path) = &cfg.audit.file {
+        registry.set_audit(AuditLog::open(path, cfg.audit.max_size, cfg.audit.keep)?);
+    }
+    let mut daemon = Daemon::new(registry);
+    daemon.set_properties(cfg.banner.properties.clone());
+    if cfg.auth.policy == AuthMode::Keys {
+        daemon.set_auth(AuthPolicy::from_files(&cfg.auth.keys, cfg.auth.accept_new_keys)?);
+    }
+    if let Some(path) = &cfg.capture.file {
+        daemon.set_capture(Capture::create(path)?);
+        info!("Capturing traffic to {}", path.display());
+    }
+    let daemon = Arc::new(daemon);
+
+    let tcp = if cfg.tcp.enabled {
+        let server = TcpServer::bind(cfg.tcp.addr())?;
+        info!("Listening on {}", server.local_addr()?);
+        let daemon = daemon.clone();
+        Some(thread::spawn(move || serve_tcp(daemon, server)))
+    } else {
+        None
+    };
+
+    if !cfg.usb.enabled {
+        return tcp.unwrap().join().unwrap();
+    }
+
+    let strings = cfg.usb.usb_strings();
+    if let Some(gadget) = &cfg.usb.gadget {
+        gadget::write_strings(gadget, &strings)?;
+    }
+
+    let endpoint_path = match cfg.usb.gadget_config() {
+        Some(gadget_cfg) => {
+            // Left in place by `adb tcpip` or `adb usb` restarting radbd
+            let kept = env::var_os(gadget::KEPT_ENV).is_some_and(|dir| gadget_cfg.dir() == dir);
+            let created = if kept {
+                gadget::Gadget::adopt(gadget_cfg, &strings)?
+            } else {
+                gadget::Gadget::create(gadget_cfg, &strings)?
+            };
+            let path = created.functionfs().to_path_buf();
+            *gadget.lock().unwrap() = Some(created);
+            let gadget = gadget.clone();
+            tcpip::at_restart(move |cmd| if let Some(gadget) = gadget.lock().unwrap().take() {
+                cmd.env(gadget::KEPT_ENV, gadget.keep());
+            });
+            path
+        },
+        None => cfg.usb.functionfs.clone().unwrap(),
+    };
+
+    let mut transport = usb::FunctionFs::open(&endpoint_path, &usb::adb_descriptors(&cfg.usb.layout()),
+                                              &strings.ffs_strings(), cfg.usb.aio)?;
+
+    if let Some(gadget) = gadget.lock().unwrap().as_mut() {
+        gadget.bind()?;
+    }
+
+    daemon.run(&mut transport)
 }
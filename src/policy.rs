@@ -0,0 +1,254 @@
+//! Which host may open which service over which transport.
+//!
+//! Rules are checked in order and the first one matching the service name,
+//! host key and transport decides, [`Policy::default`] applies when none do.
+
+use std::fmt;
+use std::path::PathBuf;
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use crate::auth::PublicKey;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// The host on the other end of a connection.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    /// [`crate::transport::Transport::kind`], `usb` or `tcp`.
+    pub transport: &'static str,
+    /// The key the host authenticated with, if auth is on.
+    pub key: Option<PublicKey>,
+    /// Whether the key is one of the configured ones rather than accepted
+    /// when the host offered it.
+    pub known_key: bool,
+}
+
+impl Peer {
+    pub fn new(transport: &'static str) -> Self {
+        Self {
+            transport,
+            key: None,
+            known_key: false,
+        }
+    }
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.key.as_ref().map(PublicKey::fingerprint)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    pub action: Action,
+    /// Service name patterns, `*` matching anything, like `sync:*`. Any
+    /// service if empty.
+    pub services: Vec<String>,
+    /// Key fingerprints, or `known` and `unknown` for keys listed in
+    /// `auth.keys` or not. Any host if empty.
+    pub keys: Vec<String>,
+    /// Transport kinds, `usb` or `tcp`. Any transport if empty.
+    pub transports: Vec<String>,
+    /// Only for services naming a port like `tcp:8080`, which then has to be
+    /// one of these.
+    pub ports: Vec<u16>,
+    /// Directories `sync:` is limited to when the rule allows it.
+    pub paths: Vec<PathBuf>,
+}
+
+impl Rule {
+    fn matches(&self, service: &str, peer: &Peer) -> bool {
+        if !self.services.is_empty() && !self.services.iter().any(|p| glob(p, service)) {
+            return false;
+        }
+        if !self.keys.is_empty() && !self.keys.iter().any(|k| key_matches(k, peer)) {
+            return false;
+        }
+        if !self.transports.is_empty() && !self.transports.iter().any(|t| t == peer.transport) {
+            return false;
+        }
+        if !self.ports.is_empty() {
+            match service_port(service) {
+                Some(port) if self.ports.contains(&port) => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+fn key_matches(key: &str, peer: &Peer) -> bool {
+    match key {
+        "known" => peer.known_key,
+        "unknown" => !peer.known_key,
+        fingerprint => peer.fingerprint().is_some_and(|f| f.eq_ignore_ascii_case(fingerprint)),
+    }
+}
+
+/// The port of `tcp:8080` or `tcp:localhost:8080`.
+fn service_port(service: &str) -> Option<u16> {
+    let rest = service.strip_prefix("tcp:")?;
+    rest.rsplit(':').next()?.parse().ok()
+}
+
+/// Matches `*` against any run of characters, everything else literally.
+fn glob(pattern: &str, text: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == text;
+    };
+    let Some(mut text) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let last = parts.pop().unwrap();
+    for part in parts {
+        match text.find(part) {
+            Some(idx) => text = &text[idx + part.len()..],
+            None => return false,
+        }
+    }
+    text.len() >= last.len() && text.ends_with(last)
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub default: Action,
+    pub rules: Vec<Rule>,
+}
+
+/// What a service got allowed to do.
+#[derive(Debug, Clone)]
+pub struct Grant<'a> {
+    pub peer: &'a Peer,
+    /// Limits sync to these directories on top of `sync.roots`.
+    pub paths: Option<&'a [PathBuf]>,
+}
+
+/// Why a service wasn't started, reported back to the host as a CLSE.
+#[derive(Debug)]
+pub struct Denied {
+    pub rule: Option<usize>,
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rule {
+            Some(idx) => write!(f, "denied by policy.rules[{}]", idx),
+            None => write!(f, "denied by the default policy"),
+        }
+    }
+}
+
+impl std::error::Error for Denied {}
+
+impl Policy {
+    pub fn check<'a>(&'a self, service: &str, peer: &'a Peer) -> Result<Grant<'a>, Denied> {
+        let found = self.rules.iter().enumerate()
+            .find(|(_, rule)| rule.matches(service, peer));
+        match found {
+            Some((idx, rule)) if rule.action == Action::Deny => Err(Denied { rule: Some(idx) }),
+            Some((_, rule)) => Ok(Grant {
+                peer,
+                paths: (!rule.paths.is_empty()).then_some(rule.paths.as_slice()),
+            }),
+            None if self.default == Action::Deny => Err(Denied { rule: None }),
+            None => Ok(Grant { peer, paths: None }),
+        }
+    }
+    /// Errors name the offending key, like [`crate::config::Config::validate`].
+    pub fn validate(&self) -> Result<()> {
+        for (idx, rule) in self.rules.iter().enumerate() {
+            for (pidx, path) in rule.paths.iter().enumerate() {
+                if !path.is_absolute() {
+                    bail!("`policy.rules[{}].paths[{}]`: {:?} isn't an absolute path", idx, pidx, path);
+                }
+            }
+            for (tidx, transport) in rule.transports.iter().enumerate() {
+                if transport != "usb" && transport != "tcp" {
+                    bail!("`policy.rules[{}].transports[{}]`: unknown transport {:?}", idx, tidx, transport);
+                }
+            }
+            for (kidx, key) in rule.keys.iter().enumerate() {
+                let hex = key.split(':').all(|b| b.len() == 2 && b.chars().all(|c| c.is_ascii_hexdigit()));
+                if key != "known" && key != "unknown" && !(hex && key.len() == 47) {
+                    bail!("`policy.rules[{}].keys[{}]`: {:?} is neither an MD5 fingerprint nor known/unknown", idx, kidx, key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(toml: &str) -> Policy {
+        let ret: Policy = toml::from_str(toml).unwrap();
+        ret.validate().unwrap();
+        ret
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("sync:*", "sync:"));
+        assert!(glob("shell:*", "shell:ls -l"));
+        assert!(glob("*:*ls*", "shell:ls"));
+        assert!(glob("a*b*c", "abbc"));
+        assert!(!glob("a*bc", "abc_"));
+        assert!(!glob("shell:", "shell:ls"));
+        assert!(!glob("a*aa", "aa"));
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let policy = policy(r#"
+            default = "deny"
+            [[rules]]
+            services = ["shell:*"]
+            keys = ["unknown"]
+            action = "deny"
+            [[rules]]
+            services = ["sync:*"]
+            transports = ["tcp"]
+            paths = ["/data"]
+            [[rules]]
+            services = ["tcp:*"]
+            ports = [8080, 8081]
+            [[rules]]
+            services = ["shell:*", "sync:*"]
+        "#);
+
+        let usb = Peer::new("usb");
+        let tcp = Peer { known_key: true, ..Peer::new("tcp") };
+
+        assert_eq!(policy.check("shell:", &usb).unwrap_err().rule, Some(0));
+        assert!(policy.check("shell:", &tcp).is_ok());
+
+        let grant = policy.check("sync:", &tcp).unwrap();
+        assert_eq!(grant.paths, Some(&[PathBuf::from("/data")][..]));
+        assert!(policy.check("sync:", &usb).unwrap().paths.is_none());
+
+        assert!(policy.check("tcp:8080", &usb).is_ok());
+        assert!(policy.check("tcp:localhost:8081", &usb).is_ok());
+        assert_eq!(policy.check("tcp:22", &usb).unwrap_err().rule, None);
+    }
+
+    #[test]
+    fn validation_names_the_key() {
+        let policy: Policy = toml::from_str(r#"
+            [[rules]]
+            [[rules]]
+            keys = ["known", "AB:CD"]
+        "#).unwrap();
+        let err = policy.validate().unwrap_err().to_string();
+        assert!(err.starts_with("`policy.rules[1].keys[1]`"), "{}", err);
+    }
+}
@@ -0,0 +1,144 @@
+//! Credentials services run with, and the `adb root`/`adb unroot` switch
+//! between them and radbd's own.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::{Context, Result};
+use nix::unistd::{self, Gid, Group, Uid, User};
+
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub user: User,
+    pub gid: Gid,
+    /// Supplementary groups.
+    pub groups: Vec<Gid>,
+    // For apply(), which mustn't allocate after a fork
+    raw_groups: Vec<libc::gid_t>,
+}
+
+impl Credentials {
+    /// Looks up `user`, with its primary group unless `group` is given.
+    pub fn lookup(user: &str, group: Option<&str>, groups: &[String]) -> Result<Self> {
+        let user = User::from_name(user)?
+            .with_context(|| format!("No such user {:?}", user))?;
+        let gid = match group {
+            Some(group) => lookup_group(group)?,
+            None => user.gid,
+        };
+        let groups: Vec<Gid> = groups.iter()
+            .map(|g| lookup_group(g))
+            .collect::<Result<_>>()?;
+        let raw_groups = groups.iter().map(|g| g.as_raw()).collect();
+        Ok(Self { user, gid, groups, raw_groups })
+    }
+    pub fn uid(&self) -> Uid {
+        self.user.uid
+    }
+    /// Switches the whole process over for good, meant for
+    /// [`std::os::unix::process::CommandExt::pre_exec`] so sticks to async
+    /// signal safe calls.
+    pub fn apply(&self) -> io::Result<()> {
+        unsafe {
+            if libc::setgroups(self.raw_groups.len(), self.raw_groups.as_ptr()) < 0
+                || libc::setresgid(self.gid.as_raw(), self.gid.as_raw(), self.gid.as_raw()) < 0
+                || libc::setresuid(self.uid().as_raw(), self.uid().as_raw(), self.uid().as_raw()) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+    /// Runs `f` with the calling thread's filesystem identity switched to
+    /// these credentials, so permission checks and new files are theirs.
+    ///
+    /// Other threads keep radbd's credentials. Without root there is nothing
+    /// to switch, `f` runs as is.
+    pub fn with_fs_ids<T>(&self, f: impl FnOnce() -> T) -> T {
+        if !unistd::geteuid().is_root() {
+            return f();
+        }
+
+        let saved = unistd::getgroups().unwrap_or_default();
+        // The raw syscalls only change this thread, unlike glibc's wrappers
+        set_thread_groups(&self.groups);
+        unsafe {
+            libc::setfsgid(self.gid.as_raw());
+            libc::setfsuid(self.uid().as_raw());
+        }
+
+        let ret = f();
+
+        unsafe {
+            libc::setfsuid(unistd::geteuid().as_raw());
+            libc::setfsgid(unistd::getegid().as_raw());
+        }
+        set_thread_groups(&saved);
+        ret
+    }
+}
+
+fn set_thread_groups(groups: &[Gid]) {
+    let groups: Vec<libc::gid_t> = groups.iter().map(|g| g.as_raw()).collect();
+    unsafe { libc::syscall(libc::SYS_setgroups, groups.len(), groups.as_ptr()) };
+}
+
+fn lookup_group(name: &str) -> Result<Gid> {
+    Ok(Group::from_name(name)?
+        .with_context(|| format!("No such group {:?}", name))?
+        .gid)
+}
+
+/// Which credentials services get, shared by every connection like adbd's
+/// root state survives a reconnect.
+#[derive(Debug)]
+pub struct Privileges {
+    user: Option<Credentials>,
+    allow_root: bool,
+    root: AtomicBool,
+}
+
+impl Privileges {
+    /// Services run as `user` unless `root`, as radbd itself without a user.
+    pub fn new(user: Option<Credentials>, allow_root: bool, root: bool) -> Self {
+        Self {
+            root: AtomicBool::new(root || user.is_none()),
+            user,
+            allow_root,
+        }
+    }
+    /// The credentials to switch to, `None` for radbd's own.
+    pub fn current(&self) -> Option<&Credentials> {
+        if self.root.load(Ordering::SeqCst) {
+            None
+        } else {
+            self.user.as_ref()
+        }
+    }
+    /// Handles `adb root`, returning what to tell the host.
+    ///
+    /// Services started from now on get the new credentials, there's no
+    /// restart, so the reply mustn't say "restarting": adb waits for the
+    /// device to go away then.
+    pub fn root(&self) -> &'static str {
+        if self.root.load(Ordering::SeqCst) {
+            "adbd is already running as root\n"
+        } else if !self.allow_root {
+            "adbd cannot run as root in production builds\n"
+        } else if !unistd::geteuid().is_root() {
+            "adbd cannot run as root, radbd itself isn't running as root\n"
+        } else {
+            self.root.store(true, Ordering::SeqCst);
+            "adbd is now running as root\n"
+        }
+    }
+    /// Handles `adb unroot`.
+    pub fn unroot(&self) -> &'static str {
+        if self.user.is_none() {
+            "adbd has no unprivileged user configured\n"
+        } else if !self.root.load(Ordering::SeqCst) {
+            "adbd not running as root\n"
+        } else {
+            self.root.store(false, Ordering::SeqCst);
+            "adbd is now running as non root\n"
+        }
+    }
+}
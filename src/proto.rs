@@ -4,45 +4,31 @@ use std::mem;
 use anyhow::{bail, Context, Result};
 
 pub fn next_msg(from: &mut impl Read) -> Result<Message> {
-    let meta;
-    let mut read = 0;
-    let mut buf = [0; MAXDATA as usize];
-
-    while read < mem::size_of::<MetaMessage>() {
-        read += from.read(&mut buf).expect("Failed to read message");
-    }
-    let mut vec = buf.to_vec();
-    vec.resize(mem::size_of::<MetaMessage>(), 0);
-    let mut cursor = Cursor::new(vec);
+    let mut header = [0; mem::size_of::<MetaMessage>()];
+    from.read_exact(&mut header)
+        .context("Failed to read message header")?;
+    let mut cursor = Cursor::new(header);
 
     let cmd = CommandType::try_from((
             cursor.read_u32::<LittleEndian>()?,
             cursor.read_u32::<LittleEndian>()?,
             cursor.read_u32::<LittleEndian>()?))
         .context("Failed to get command type")?;
-    meta = MetaMessage {
+    let meta = MetaMessage {
         cmd,
         len: cursor.read_u32::<LittleEndian>()?,
         crc: cursor.read_u32::<LittleEndian>()?,
         magic: cursor.read_u32::<LittleEndian>()?,
     };
 
-    let mut data;
-
-    if meta.len > 0 {
-        let mut buf = [0; MAXDATA as usize];
-        read = cursor.read(&mut buf).unwrap();
-        while read < meta.len as usize {
-            read += from.read(&mut buf).expect("Failed to read data");
-        }
-        assert_eq!(meta.len as usize, read);
-
-        data = buf.to_vec();
-        data.resize(meta.len as usize, 0);
-    } else {
-        data = Vec::new();
+    if meta.len > MAXDATA {
+        bail!("Payload of {} bytes is over the limit of {}", meta.len, MAXDATA);
     }
 
+    let mut data = vec![0; meta.len as usize];
+    from.read_exact(&mut data)
+        .context("Failed to read message payload")?;
+
     Ok(Message {
         meta,
         data,
@@ -107,6 +93,10 @@ impl Message {
         let cmd = CommandType::Close{local_id, remote_id};
         Self::mk_msg(cmd, Vec::new())
     }
+    pub fn auth(ty: u32, data: Vec<u8>) -> Self {
+        let cmd = CommandType::Auth{ty, zero: 0};
+        Self::mk_msg(cmd, data)
+    }
     fn mk_msg(cmd: CommandType, data: Vec<u8>) -> Self {
         let magic = cmd.magic();
         Self {
@@ -119,12 +109,12 @@ impl Message {
             data,
         }
     }
-    pub fn to_bytes(self) -> (Vec<u8>, Vec<u8>) {
+    pub fn into_bytes(self) -> (Vec<u8>, Vec<u8>) {
         (self.meta.bytes().to_vec(), self.data)
     }
     pub fn send_to(self, to_where: &mut impl io::Write) -> Result<()> {
-        println!("tx: {:#x?}", self.meta());
-        let (header, data) = self.to_bytes();
+        log::trace!("tx: {:x?}", self.meta());
+        let (header, data) = self.into_bytes();
         to_where.write_all(&header)
             .context("Failed to write header")?;
         to_where.write_all(&data)
@@ -151,6 +141,7 @@ pub const A_STLS_MAGIC: u32 = 0xffffffff ^ A_STLS;
 
 #[derive(Debug, Clone)]
 #[repr(u32, C)]
+#[allow(dead_code)]
 pub enum CommandType {
     Connect{version: u32, maxdata: u32} = A_CNXN,
     Stls{ty: u32, version: u32} = A_STLS,
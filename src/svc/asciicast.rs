@@ -0,0 +1,171 @@
+//! Records shell sessions as asciicast v2, replayable with `asciinema play`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use log::warn;
+use crate::audit::push_json_str;
+use crate::config::RecordingConfig;
+
+pub struct Recorder {
+    file: File,
+    path: PathBuf,
+    start: Instant,
+    written: u64,
+    max_size: u64,
+    input: bool,
+    // Trailing bytes of a UTF-8 sequence split across reads, per direction
+    pending: [Vec<u8>; 2],
+    stopped: bool,
+}
+
+impl Recorder {
+    /// Starts a new file in `cfg.dir`, dropping the oldest ones beyond
+    /// `cfg.keep`.
+    pub fn create(cfg: &RecordingConfig, stream: u32, size: (u16, u16), title: &str) -> Result<Self> {
+        fs::create_dir_all(&cfg.dir)
+            .with_context(|| format!("Failed to create {:?}", cfg.dir))?;
+        prune(&cfg.dir, cfg.keep.saturating_sub(1))?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let path = cfg.dir.join(format!("{}-{}-{}.cast", now.as_millis(), process::id(), stream));
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("Failed to create {:?}", path))?;
+
+        let mut header = format!("{{\"version\":2,\"width\":{},\"height\":{},\"timestamp\":{},\"title\":",
+                                 size.0, size.1, now.as_secs());
+        push_json_str(&mut header, title);
+        header.push_str("}\n");
+
+        let mut ret = Self {
+            file,
+            path,
+            start: Instant::now(),
+            written: 0,
+            max_size: cfg.max_size,
+            input: cfg.input,
+            pending: [Vec::new(), Vec::new()],
+            stopped: false,
+        };
+        ret.write(&header);
+        Ok(ret)
+    }
+    pub fn output(&mut self, data: &[u8]) {
+        self.event(0, "o", data);
+    }
+    /// Only recorded if the config asks for input, it has passwords in it.
+    pub fn input(&mut self, data: &[u8]) {
+        if self.input {
+            self.event(1, "i", data);
+        }
+    }
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        self.event(0, "r", format!("{}x{}", cols, rows).as_bytes());
+    }
+    fn event(&mut self, dir: usize, code: &str, data: &[u8]) {
+        if self.stopped {
+            return;
+        }
+
+        let mut buf = std::mem::take(&mut self.pending[dir]);
+        buf.extend(data);
+        let valid = match std::str::from_utf8(&buf) {
+            Ok(_) => buf.len(),
+            // Incomplete sequence at the end, wait for the rest
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => buf.len(),
+        };
+        self.pending[dir] = buf.split_off(valid);
+        if buf.is_empty() {
+            return;
+        }
+
+        let mut line = format!("[{:.6},\"{}\",", self.start.elapsed().as_secs_f64(), code);
+        push_json_str(&mut line, &String::from_utf8_lossy(&buf));
+        line.push_str("]\n");
+        self.write(&line);
+    }
+    fn write(&mut self, line: &str) {
+        if self.written + line.len() as u64 > self.max_size {
+            warn!("Recording {:?} reached its size limit, stopping it", self.path);
+            self.stopped = true;
+            return;
+        }
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            warn!("Failed to write recording {:?}: {}", self.path, e);
+            self.stopped = true;
+            return;
+        }
+        self.written += line.len() as u64;
+    }
+}
+
+/// Removes the oldest recordings until at most `keep` are left.
+fn prune(dir: &Path, keep: usize) -> Result<()> {
+    let mut casts: Vec<(SystemTime, PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "cast"))
+        .filter_map(|p| Some((p.metadata().ok()?.modified().ok()?, p)))
+        .collect();
+    casts.sort();
+
+    let excess = casts.len().saturating_sub(keep);
+    for (_, path) in &casts[..excess] {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Failed to remove old recording {:?}: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn records_and_prunes() {
+        let dir = env::temp_dir().join(format!("radbd-casts-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cfg = RecordingConfig {
+            dir: dir.clone(),
+            input: false,
+            max_size: 200,
+            keep: 2,
+        };
+
+        let mut rec = Recorder::create(&cfg, 3, (80, 24), "shell:").unwrap();
+        rec.input(b"secret\r");
+        // "é" split in two reads
+        rec.output(b"caf\xc3");
+        rec.output(b"\xa9\r\n$ ");
+        rec.output(&[b'x'; 300]);
+        let path = rec.path.clone();
+        drop(rec);
+
+        let data = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = data.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with(r#"{"version":2,"width":80,"height":24,"timestamp":"#));
+        assert!(lines[0].ends_with(r#","title":"shell:"}"#));
+        assert!(lines[1].starts_with('['));
+        assert!(lines[1].ends_with(r#","o","caf"]"#), "{}", lines[1]);
+        assert!(lines[2].ends_with(r#","o","é\r\n$ "]"#), "{}", lines[2]);
+
+        for stream in 4..7 {
+            Recorder::create(&cfg, stream, (80, 24), "shell:").unwrap();
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
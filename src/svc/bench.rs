@@ -0,0 +1,147 @@
+//! `sink:N` and `source:N`, which consume and produce N bytes like adbd's
+//! to measure how fast streams move data.
+
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use log::info;
+use crate::proto::MAXDATA;
+use crate::svc::{Service, Waker, OUTPUT_QUEUE_LEN};
+
+/// Counts what went through a stream and logs the rate when it closes.
+struct Throughput {
+    name: &'static str,
+    stream: u32,
+    size: u64,
+    bytes: u64,
+    start: Instant,
+}
+
+impl Throughput {
+    fn new(name: &'static str, arg: &str, waker: &Waker) -> Result<Self> {
+        let size = arg.parse()
+            .with_context(|| format!("{}: invalid byte count {:?}", name, arg))?;
+        Ok(Self { name, stream: waker.id(), size, bytes: 0, start: Instant::now() })
+    }
+    fn left(&self) -> u64 {
+        self.size - self.bytes
+    }
+    fn report(&self) {
+        info!("Stream {} {}: {} of {} bytes, {}", self.stream, self.name, self.bytes, self.size,
+              rate(self.bytes, self.start.elapsed()));
+    }
+}
+
+fn rate(bytes: u64, time: Duration) -> String {
+    let secs = time.as_secs_f64();
+    let mib = bytes as f64 / (1 << 20) as f64;
+    format!("{:.3}s at {:.1} MiB/s", secs, if secs > 0.0 { mib / secs } else { 0.0 })
+}
+
+pub struct SinkService {
+    rx: Receiver<Vec<u8>>,
+    count: Throughput,
+}
+
+impl Service for SinkService {
+    fn handle_write(&mut self, data: Vec<u8>) -> Result<()> {
+        if data.len() as u64 > self.count.left() {
+            bail!("sink got {} bytes more than the {} it was asked for",
+                  data.len() as u64 - self.count.left(), self.count.size);
+        }
+        self.count.bytes += data.len() as u64;
+        Ok(())
+    }
+    fn recv(&mut self) -> &mut Receiver<Vec<u8>> {
+        &mut self.rx
+    }
+    fn is_done(&mut self) -> bool {
+        self.count.left() == 0
+    }
+    fn close(&mut self) -> Result<()> {
+        self.count.report();
+        Ok(())
+    }
+}
+
+impl SinkService {
+    pub fn start(arg: &str, waker: Waker) -> Result<Box<dyn Service>> {
+        let (_, rx) = crossbeam_channel::bounded(1);
+        Ok(Box::new(Self { rx, count: Throughput::new("sink", arg, &waker)? }))
+    }
+}
+
+pub struct SourceService {
+    rx: Receiver<Vec<u8>>,
+    tx: Option<Sender<Vec<u8>>>,
+    count: Throughput,
+}
+
+impl Service for SourceService {
+    fn handle_write(&mut self, _data: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+    /// Tops the queue up first, the data is made as fast as the stream
+    /// takes it without a thread of its own.
+    fn recv(&mut self) -> &mut Receiver<Vec<u8>> {
+        while let Some(tx) = self.tx.as_ref().filter(|tx| !tx.is_full()) {
+            let len = self.count.left().min(MAXDATA as u64);
+            if len == 0 {
+                self.tx = None;
+                break;
+            }
+            let _ = tx.try_send(vec![0; len as usize]);
+            self.count.bytes += len;
+        }
+        &mut self.rx
+    }
+    fn is_done(&mut self) -> bool {
+        self.tx.is_none()
+    }
+    fn close(&mut self) -> Result<()> {
+        // What's still queued never reached the host
+        self.count.bytes -= self.rx.try_iter().map(|chunk| chunk.len() as u64).sum::<u64>();
+        self.count.report();
+        Ok(())
+    }
+}
+
+impl SourceService {
+    pub fn start(arg: &str, waker: Waker) -> Result<Box<dyn Service>> {
+        let (tx, rx) = crossbeam_channel::bounded(OUTPUT_QUEUE_LEN);
+        Ok(Box::new(Self { rx, tx: Some(tx), count: Throughput::new("source", arg, &waker)? }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_exactly_n_bytes() {
+        let (wake_tx, _wake_rx) = crossbeam_channel::unbounded();
+        let waker = Waker::new(1, wake_tx);
+        let size = 3 * MAXDATA as usize + 10;
+
+        let mut source = SourceService::start(&size.to_string(), waker.clone()).unwrap();
+        let mut got = Vec::new();
+        while let Ok(chunk) = source.recv().try_recv() {
+            assert!(chunk.len() <= MAXDATA as usize);
+            got.push(chunk.len());
+        }
+        assert!(source.is_done());
+        source.close().unwrap();
+        assert_eq!(got.iter().sum::<usize>(), size);
+
+        let mut sink = SinkService::start(&size.to_string(), waker.clone()).unwrap();
+        for len in got {
+            assert!(!sink.is_done());
+            sink.handle_write(vec![0; len]).unwrap();
+        }
+        assert!(sink.is_done());
+        assert!(sink.handle_write(vec![0]).is_err());
+        assert!(SinkService::start("lots", waker).is_err());
+
+        assert_eq!(rate(3 << 20, Duration::from_millis(1500)), "1.500s at 2.0 MiB/s");
+    }
+}
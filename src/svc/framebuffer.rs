@@ -0,0 +1,425 @@
+//! `framebuffer:`, a screenshot of fbdev or DRM with adb's version 1 header
+//! in front.
+
+use std::fs::{File, OpenOptions};
+use std::num::NonZeroUsize;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::slice;
+use anyhow::{bail, Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use crate::config::{FramebufferConfig, FramebufferMode};
+use crate::proto::MAXDATA;
+use crate::svc::{Service, OUTPUT_QUEUE_LEN};
+
+/// Where each color sits in a pixel, as bit offset and length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub bpp: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes from one line to the next in the source, can have padding.
+    pub stride: u32,
+    pub red: (u32, u32),
+    pub green: (u32, u32),
+    pub blue: (u32, u32),
+    pub alpha: (u32, u32),
+}
+
+impl Format {
+    /// Tightly packed pixels in one of the formats Android knows by name.
+    pub fn named(name: &str, width: u32, height: u32) -> Result<Self> {
+        // Little endian, so the first byte is the lowest bits
+        let (bpp, red, green, blue, alpha) = match name {
+            "rgba8888" => (32, (0, 8), (8, 8), (16, 8), (24, 8)),
+            "rgbx8888" => (32, (0, 8), (8, 8), (16, 8), (24, 0)),
+            "bgra8888" => (32, (16, 8), (8, 8), (0, 8), (24, 8)),
+            "rgb888" => (24, (0, 8), (8, 8), (16, 8), (0, 0)),
+            "rgb565" => (16, (11, 5), (5, 6), (0, 5), (0, 0)),
+            _ => bail!("Unknown pixel format {:?}, known ones are rgba8888, rgbx8888, bgra8888, rgb888 and rgb565", name),
+        };
+        Ok(Self { bpp, width, height, stride: width * bpp / 8, red, green, blue, alpha })
+    }
+    fn line_len(&self) -> usize {
+        (self.width * self.bpp / 8) as usize
+    }
+    /// The header the host reads first, 13 little endian u32s.
+    pub fn header(&self) -> Vec<u8> {
+        let fields = [
+            1,
+            self.bpp,
+            self.line_len() as u32 * self.height,
+            self.width,
+            self.height,
+            self.red.0, self.red.1,
+            self.blue.0, self.blue.1,
+            self.green.0, self.green.1,
+            self.alpha.0, self.alpha.1,
+        ];
+        fields.iter().flat_map(|f| f.to_le_bytes()).collect()
+    }
+    /// Drops the padding at the end of each line of `src`.
+    pub fn pack(&self, src: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(self.line_len() * self.height as usize);
+        for line in src.chunks(self.stride as usize).take(self.height as usize) {
+            ret.extend(&line[..self.line_len().min(line.len())]);
+        }
+        ret
+    }
+}
+
+/// Everything the host gets, header and pixels.
+pub fn capture(cfg: &FramebufferConfig) -> Result<Vec<u8>> {
+    let device = &cfg.device;
+    let (format, pixels) = match &cfg.mode {
+        Some(mode) => read_raw(device, mode)?,
+        None if device.starts_with("/dev/dri") => drm::read(device)?,
+        None => fbdev::read(device)?,
+    };
+    let mut ret = format.header();
+    ret.extend(format.pack(&pixels));
+    Ok(ret)
+}
+
+/// A file with nothing but pixels in it, of a size the config knows.
+fn read_raw(path: &Path, mode: &FramebufferMode) -> Result<(Format, Vec<u8>)> {
+    let format = Format::named(&mode.format, mode.width, mode.height)?;
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    let mut pixels = vec![0; format.stride as usize * format.height as usize];
+    file.read_exact_at(&mut pixels, 0)
+        .with_context(|| format!("{:?} is smaller than a {}x{} {} frame", path, mode.width, mode.height, mode.format))?;
+    Ok((format, pixels))
+}
+
+mod fbdev {
+    use super::*;
+
+    #[repr(C)]
+    #[derive(Default)]
+    #[allow(dead_code)]
+    struct Bitfield {
+        offset: u32,
+        length: u32,
+        msb_right: u32,
+    }
+
+    /// struct fb_var_screeninfo
+    #[repr(C)]
+    #[derive(Default)]
+    #[allow(dead_code)]
+    struct VarInfo {
+        xres: u32,
+        yres: u32,
+        xres_virtual: u32,
+        yres_virtual: u32,
+        xoffset: u32,
+        yoffset: u32,
+        bits_per_pixel: u32,
+        grayscale: u32,
+        red: Bitfield,
+        green: Bitfield,
+        blue: Bitfield,
+        transp: Bitfield,
+        rest: [u32; 20],
+    }
+
+    /// struct fb_fix_screeninfo
+    #[repr(C)]
+    #[derive(Default)]
+    #[allow(dead_code)]
+    struct FixInfo {
+        id: [u8; 16],
+        smem_start: libc::c_ulong,
+        smem_len: u32,
+        type_: u32,
+        type_aux: u32,
+        visual: u32,
+        xpanstep: u16,
+        ypanstep: u16,
+        ywrapstep: u16,
+        line_length: u32,
+        mmio_start: libc::c_ulong,
+        mmio_len: u32,
+        accel: u32,
+        capabilities: u16,
+        reserved: [u16; 2],
+    }
+
+    static_assertions::assert_eq_size!(VarInfo, [u8; 160]);
+
+    nix::ioctl_read_bad!(get_var_info, 0x4600, VarInfo);
+    nix::ioctl_read_bad!(get_fix_info, 0x4602, FixInfo);
+
+    pub fn read(path: &Path) -> Result<(Format, Vec<u8>)> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open {:?}", path))?;
+        let (mut var, mut fix) = (VarInfo::default(), FixInfo::default());
+        unsafe {
+            get_var_info(file.as_raw_fd(), &mut var)
+                .and_then(|_| get_fix_info(file.as_raw_fd(), &mut fix))
+                .with_context(|| format!("{:?} isn't a framebuffer, set framebuffer.mode for a raw file", path))?;
+        }
+
+        let format = Format {
+            bpp: var.bits_per_pixel,
+            width: var.xres,
+            height: var.yres,
+            stride: fix.line_length,
+            red: (var.red.offset, var.red.length),
+            green: (var.green.offset, var.green.length),
+            blue: (var.blue.offset, var.blue.length),
+            alpha: (var.transp.offset, var.transp.length),
+        };
+        // The visible part of a double buffered framebuffer moves around
+        let start = var.yoffset as u64 * fix.line_length as u64 + (var.xoffset * var.bits_per_pixel / 8) as u64;
+        let mut pixels = vec![0; format.stride as usize * format.height as usize];
+        file.read_exact_at(&mut pixels, start)
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        Ok((format, pixels))
+    }
+}
+
+mod drm {
+    use super::*;
+
+    /// struct drm_mode_card_res
+    #[repr(C)]
+    #[derive(Default)]
+    #[allow(dead_code)]
+    struct CardRes {
+        fb_id_ptr: u64,
+        crtc_id_ptr: u64,
+        connector_id_ptr: u64,
+        encoder_id_ptr: u64,
+        count_fbs: u32,
+        count_crtcs: u32,
+        count_connectors: u32,
+        count_encoders: u32,
+        min_width: u32,
+        max_width: u32,
+        min_height: u32,
+        max_height: u32,
+    }
+
+    /// struct drm_mode_crtc, with the mode as bytes as nothing here needs it
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct Crtc {
+        set_connectors_ptr: u64,
+        count_connectors: u32,
+        crtc_id: u32,
+        fb_id: u32,
+        x: u32,
+        y: u32,
+        gamma_size: u32,
+        mode_valid: u32,
+        mode: [u8; 68],
+    }
+
+    /// struct drm_mode_fb_cmd
+    #[repr(C)]
+    #[derive(Default)]
+    #[allow(dead_code)]
+    struct FbCmd {
+        fb_id: u32,
+        width: u32,
+        height: u32,
+        pitch: u32,
+        bpp: u32,
+        depth: u32,
+        handle: u32,
+    }
+
+    /// struct drm_mode_map_dumb
+    #[repr(C)]
+    #[derive(Default)]
+    #[allow(dead_code)]
+    struct MapDumb {
+        handle: u32,
+        pad: u32,
+        offset: u64,
+    }
+
+    /// struct drm_gem_close
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct GemClose {
+        handle: u32,
+        pad: u32,
+    }
+
+    static_assertions::assert_eq_size!(CardRes, [u8; 64]);
+    static_assertions::assert_eq_size!(Crtc, [u8; 104]);
+    static_assertions::assert_eq_size!(FbCmd, [u8; 28]);
+    static_assertions::assert_eq_size!(MapDumb, [u8; 16]);
+
+    nix::ioctl_readwrite!(get_resources, b'd', 0xa0, CardRes);
+    nix::ioctl_readwrite!(get_crtc, b'd', 0xa1, Crtc);
+    nix::ioctl_readwrite!(get_fb, b'd', 0xad, FbCmd);
+    nix::ioctl_readwrite!(map_dumb, b'd', 0xb3, MapDumb);
+    nix::ioctl_write_ptr!(gem_close, b'd', 0x09, GemClose);
+
+    /// Reads the framebuffer of the first active CRTC, which takes
+    /// CAP_SYS_ADMIN for the kernel to hand out its buffer.
+    pub fn read(path: &Path) -> Result<(Format, Vec<u8>)> {
+        let card = OpenOptions::new().read(true).write(true).open(path)
+            .with_context(|| format!("Failed to open {:?}", path))?;
+        let fd = card.as_raw_fd();
+
+        let mut res = CardRes::default();
+        unsafe { get_resources(fd, &mut res) }
+            .with_context(|| format!("{:?} isn't a DRM device", path))?;
+        let mut crtcs = vec![0u32; res.count_crtcs as usize];
+        res = CardRes {
+            crtc_id_ptr: crtcs.as_mut_ptr() as u64,
+            count_crtcs: crtcs.len() as u32,
+            ..CardRes::default()
+        };
+        unsafe { get_resources(fd, &mut res) }?;
+        crtcs.truncate(res.count_crtcs as usize);
+
+        let fb_id = crtcs.iter()
+            .find_map(|&crtc_id| {
+                let mut crtc = Crtc {
+                    set_connectors_ptr: 0,
+                    count_connectors: 0,
+                    crtc_id,
+                    fb_id: 0,
+                    x: 0,
+                    y: 0,
+                    gamma_size: 0,
+                    mode_valid: 0,
+                    mode: [0; 68],
+                };
+                unsafe { get_crtc(fd, &mut crtc) }.ok()?;
+                (crtc.mode_valid != 0 && crtc.fb_id != 0).then_some(crtc.fb_id)
+            })
+            .with_context(|| format!("Nothing is on screen on {:?}", path))?;
+
+        let mut fb = FbCmd { fb_id, ..FbCmd::default() };
+        unsafe { get_fb(fd, &mut fb) }.context("Failed to get the framebuffer")?;
+        if fb.handle == 0 {
+            bail!("The kernel only hands out framebuffers with CAP_SYS_ADMIN");
+        }
+        let ret = map_fb(fd, &fb);
+        let _ = unsafe { gem_close(fd, &GemClose { handle: fb.handle, pad: 0 }) };
+        ret
+    }
+
+    fn map_fb(fd: i32, fb: &FbCmd) -> Result<(Format, Vec<u8>)> {
+        // depth 24 is XRGB8888, 32 ARGB8888, both blue first in memory
+        let format = match (fb.bpp, fb.depth) {
+            (32, 24) => Format { alpha: (24, 0), ..Format::named("bgra8888", fb.width, fb.height)? },
+            (32, 32) => Format::named("bgra8888", fb.width, fb.height)?,
+            (16, 16) => Format::named("rgb565", fb.width, fb.height)?,
+            (bpp, depth) => bail!("Unsupported framebuffer with {} bpp and depth {}", bpp, depth),
+        };
+        let format = Format { stride: fb.pitch, ..format };
+
+        let mut map = MapDumb { handle: fb.handle, ..MapDumb::default() };
+        unsafe { map_dumb(fd, &mut map) }.context("Failed to map the framebuffer")?;
+        let len = fb.pitch as usize * fb.height as usize;
+        let Some(nonzero_len) = NonZeroUsize::new(len) else { bail!("Empty framebuffer"); };
+        unsafe {
+            let ptr = mmap(None, nonzero_len, ProtFlags::PROT_READ, MapFlags::MAP_SHARED, fd, map.offset as libc::off_t)?;
+            let pixels = slice::from_raw_parts(ptr as *const u8, len).to_vec();
+            let _ = munmap(ptr, len);
+            Ok((format, pixels))
+        }
+    }
+}
+
+pub struct FramebufferService {
+    rx: Receiver<Vec<u8>>,
+    tx: Sender<Vec<u8>>,
+    data: Vec<u8>,
+    /// How much of `data` is queued already.
+    queued: usize,
+}
+
+impl Service for FramebufferService {
+    fn handle_write(&mut self, _data: Vec<u8>) -> Result<()> {
+        // Old clients nudge before reading the pixels, they come anyway
+        Ok(())
+    }
+    /// Tops the queue up from the capture as the stream takes from it.
+    fn recv(&mut self) -> &mut Receiver<Vec<u8>> {
+        while self.queued < self.data.len() {
+            let end = self.data.len().min(self.queued + MAXDATA as usize);
+            if self.tx.try_send(self.data[self.queued..end].to_vec()).is_err() {
+                break;
+            }
+            self.queued = end;
+        }
+        &mut self.rx
+    }
+    fn is_done(&mut self) -> bool {
+        self.queued == self.data.len()
+    }
+}
+
+impl FramebufferService {
+    pub fn start(cfg: &FramebufferConfig) -> Result<Box<dyn Service>> {
+        let data = capture(cfg)?;
+        let (tx, rx) = crossbeam_channel::bounded(OUTPUT_QUEUE_LEN);
+        Ok(Box::new(Self { rx, tx, data, queued: 0 }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn captures_a_fake_framebuffer() {
+        let path = env::temp_dir().join(format!("radbd-fb-{}", process::id()));
+        // 3x2 rgb565, then a line too many that mustn't show up
+        let pixels: Vec<u8> = (0..18).collect();
+        fs::write(&path, &pixels).unwrap();
+
+        let cfg = FramebufferConfig {
+            device: path.clone(),
+            mode: Some(FramebufferMode { width: 3, height: 2, format: "rgb565".to_string() }),
+        };
+        let data = capture(&cfg).unwrap();
+        let header: Vec<u32> = data[..52].chunks(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect();
+        assert_eq!(header, [1, 16, 12, 3, 2, 11, 5, 0, 5, 5, 6, 0, 0]);
+        assert_eq!(&data[52..], &pixels[..12]);
+
+        let too_big = FramebufferConfig {
+            mode: Some(FramebufferMode { width: 4, height: 4, format: "rgba8888".to_string() }),
+            ..cfg
+        };
+        assert!(capture(&too_big).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn queues_a_large_capture_bit_by_bit() {
+        let path = env::temp_dir().join(format!("radbd-fb-large-{}", process::id()));
+        fs::write(&path, vec![7; 1024 * 768 * 4]).unwrap();
+        let cfg = FramebufferConfig {
+            device: path.clone(),
+            mode: Some(FramebufferMode { width: 1024, height: 768, format: "rgba8888".to_string() }),
+        };
+        let mut svc = FramebufferService::start(&cfg).unwrap();
+        let mut data = Vec::new();
+        while let Ok(chunk) = svc.recv().try_recv() {
+            assert!(svc.recv().len() <= OUTPUT_QUEUE_LEN);
+            data.extend(chunk);
+        }
+        assert!(svc.is_done());
+        assert_eq!(data, capture(&cfg).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn packs_padded_lines() {
+        let format = Format { stride: 8, ..Format::named("rgb565", 3, 2).unwrap() };
+        assert_eq!(format.pack(&[1, 1, 2, 2, 3, 3, 0, 0, 4, 4, 5, 5, 6, 6, 0, 0]), [1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6]);
+    }
+}
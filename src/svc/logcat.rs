@@ -0,0 +1,437 @@
+//! `logcat:`, the kernel log and log files streamed like `adb logcat` shows
+//! them.
+//!
+//! Arguments follow logcat: filter specs like `sshd:I *:W`, and `-d` to
+//! dump what's there and exit instead of following.
+//!
+//! `adb logcat` itself runs logcat through the shell, the shell services
+//! hand commands like that over to this one, see [`from_shell`].
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use anyhow::{bail, Result};
+use crossbeam_channel::{Receiver, Sender};
+use log::{debug, warn};
+use nix::time::{clock_gettime, ClockId};
+use crate::config::LogcatConfig;
+use crate::logger;
+use crate::svc::{Service, Waker, OUTPUT_QUEUE_LEN};
+use crate::svc::shell::{id, packet};
+
+/// How often followed sources get checked for something new.
+const POLL: Duration = Duration::from_millis(250);
+/// How much of a log file's end gets shown before following it.
+const TAIL_BYTES: u64 = 64 << 10;
+
+/// logcat's priorities, `Silent` only makes sense in a filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Verbose,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+    Silent,
+}
+
+impl Level {
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "V" => Level::Verbose,
+            "D" => Level::Debug,
+            "I" => Level::Info,
+            "W" => Level::Warn,
+            "E" => Level::Error,
+            "F" => Level::Fatal,
+            "S" => Level::Silent,
+            _ => return None,
+        })
+    }
+    /// From a syslog severity, 0 for emerg to 7 for debug.
+    fn from_syslog(sev: u32) -> Self {
+        match sev {
+            0..=2 => Level::Fatal,
+            3 => Level::Error,
+            4 => Level::Warn,
+            5 | 6 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+    fn letter(self) -> char {
+        b"VDIWEFS"[self as usize] as char
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    default: Level,
+    tags: Vec<(String, Level)>,
+}
+
+impl Filter {
+    /// Parses logcat filter specs, `TAG:LEVEL` with `*` for every other tag.
+    pub fn parse<'a>(specs: impl IntoIterator<Item = &'a str>) -> Result<Self> {
+        let mut ret = Self { default: Level::Verbose, tags: Vec::new() };
+        for spec in specs {
+            let (tag, level) = spec.rsplit_once(':').unwrap_or((spec, "V"));
+            let Some(level) = Level::parse(level) else {
+                bail!("Invalid level in filter spec {:?}, expected one of V D I W E F S", spec);
+            };
+            if tag == "*" {
+                ret.default = level;
+            } else {
+                ret.tags.push((tag.to_string(), level));
+            }
+        }
+        Ok(ret)
+    }
+    pub fn allows(&self, tag: &str, level: Level) -> bool {
+        let min = self.tags.iter()
+            .rfind(|(t, _)| t == tag)
+            .map_or(self.default, |(_, l)| *l);
+        min != Level::Silent && level >= min
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    time: SystemTime,
+    level: Level,
+    tag: String,
+    message: String,
+}
+
+impl Entry {
+    fn format(&self) -> String {
+        format!("{} {} {}: {}\n", logger::timestamp(self.time), self.level.letter(), self.tag, self.message)
+    }
+}
+
+/// Parses a /dev/kmsg record, `PRI,SEQ,USEC,FLAGS;MESSAGE` followed by
+/// indented `KEY=VALUE` lines. `boot` is the wall-clock time USEC counts from.
+fn parse_kmsg(record: &str, boot: SystemTime) -> Option<Entry> {
+    let (head, rest) = record.split_once(';')?;
+    let mut fields = head.split(',');
+    let pri: u32 = fields.next()?.parse().ok()?;
+    let usec: u64 = fields.nth(1)?.parse().ok()?;
+    let message = rest.lines().next().unwrap_or("");
+    Some(Entry {
+        time: boot + Duration::from_micros(usec),
+        level: Level::from_syslog(pri & 7),
+        tag: "kernel".to_string(),
+        message: message.to_string(),
+    })
+}
+
+/// Takes the tag from a syslog style line like
+/// `Oct 18 23:44:02 board sshd[412]: Accepted key`. Files carry no
+/// priority, so it's always info.
+fn parse_line(line: &str) -> Entry {
+    let (tag, message) = match line.split_once(": ") {
+        Some((head, message)) => {
+            let word = head.rsplit(' ').next().unwrap_or(head);
+            (word.split('[').next().unwrap_or(word), message)
+        },
+        None => ("", line),
+    };
+    Entry {
+        time: SystemTime::now(),
+        level: Level::Info,
+        tag: if tag.is_empty() { "syslog" } else { tag }.to_string(),
+        message: message.to_string(),
+    }
+}
+
+pub struct LogcatService {
+    rx: Receiver<Vec<u8>>,
+    stop: Arc<AtomicBool>,
+    done: Arc<AtomicBool>,
+}
+
+impl Service for LogcatService {
+    fn handle_write(&mut self, _data: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+    fn close(&mut self) -> Result<()> {
+        self.stop.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+    fn recv(&mut self) -> &mut Receiver<Vec<u8>> {
+        &mut self.rx
+    }
+    fn is_done(&mut self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+}
+
+/// Hands entries that pass the filter on to the stream.
+#[derive(Clone)]
+struct Sink {
+    tx: Sender<Vec<u8>>,
+    filter: Arc<Filter>,
+    stop: Arc<AtomicBool>,
+    /// Whether the host asked through the shell with protocol v2.
+    v2: bool,
+}
+
+impl Sink {
+    /// False once the stream is gone.
+    fn send(&self, entry: Entry) -> bool {
+        if !self.filter.allows(&entry.tag, entry.level) {
+            return !self.stopped();
+        }
+        let line = entry.format();
+        self.tx.send(if self.v2 { packet(id::STDOUT, line.as_bytes()) } else { line.into_bytes() }).is_ok()
+    }
+    /// Ends the output like logcat exiting would.
+    fn exit(&self) {
+        if self.v2 {
+            let _ = self.tx.send(packet(id::EXIT, &[0]));
+        }
+    }
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+}
+
+impl LogcatService {
+    /// `v2` frames the output as shell protocol v2 for [`from_shell`].
+    pub fn start(arg: &str, cfg: &LogcatConfig, v2: bool, waker: Waker) -> Result<Box<dyn Service>> {
+        let mut dump = false;
+        let mut specs = Vec::new();
+        for arg in arg.split_whitespace() {
+            match arg {
+                "-d" => dump = true,
+                _ if arg.starts_with('-') => bail!("Unsupported logcat option {:?}", arg),
+                _ => specs.push(arg),
+            }
+        }
+
+        let (tx, rx) = crossbeam_channel::bounded(OUTPUT_QUEUE_LEN);
+        let stop = Arc::new(AtomicBool::new(false));
+        let sink = Sink { tx, filter: Arc::new(Filter::parse(specs)?), stop: stop.clone(), v2 };
+        let done = Arc::new(AtomicBool::new(false));
+
+        // None for the kernel log
+        let mut sources: Vec<Option<PathBuf>> = cfg.files.iter().cloned().map(Some).collect();
+        if cfg.kernel {
+            sources.push(None);
+        }
+
+        if sources.is_empty() {
+            sink.exit();
+            done.store(true, Ordering::SeqCst);
+        }
+        let running = Arc::new(AtomicUsize::new(sources.len()));
+        for source in sources {
+            let (sink, running, done, waker) = (sink.clone(), running.clone(), done.clone(), waker.clone());
+            thread::spawn(move || {
+                let ret = match &source {
+                    Some(path) => follow_file(path, &sink, dump),
+                    None => follow_kmsg(&sink, dump),
+                };
+                if let Err(e) = ret {
+                    warn!("Failed to read {:?}: {}", source.as_deref().unwrap_or(Path::new("/dev/kmsg")), e);
+                }
+                if running.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    sink.exit();
+                    done.store(true, Ordering::SeqCst);
+                    waker.wake();
+                }
+            });
+        }
+
+        Ok(Box::new(Self { rx, stop, done }))
+    }
+}
+
+/// The logcat arguments of a shell command that runs logcat, None if it
+/// does anything else.
+///
+/// Takes what `adb logcat -d sshd:I` sends,
+/// `export ANDROID_LOG_TAGS="''"; exec logcat '-d' 'sshd:I'`, as well as a
+/// plain `logcat -d`. Tags from ANDROID_LOG_TAGS go first, so the
+/// arguments win.
+pub fn from_shell(cmd: &str) -> Option<String> {
+    let mut rest = cmd.trim();
+    let mut ret = Vec::new();
+    if let Some(export) = rest.strip_prefix("export ANDROID_LOG_TAGS=") {
+        let (tags, after) = export.split_once(';')?;
+        ret.extend(tags.trim().trim_matches(['"', '\'']).split_whitespace().map(str::to_string));
+        rest = after.trim_start();
+    }
+    rest = rest.strip_prefix("exec ").map_or(rest, str::trim_start);
+    let args = rest.strip_prefix("logcat")?;
+    if !args.is_empty() && !args.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    for arg in args.split_whitespace() {
+        let arg = match arg.strip_prefix('\'').and_then(|a| a.strip_suffix('\'')) {
+            Some(quoted) if !quoted.contains('\'') => quoted,
+            // Pipes, redirections and the like are the shell's business
+            _ if arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:*".contains(c)) => arg,
+            _ => return None,
+        };
+        ret.push(arg.to_string());
+    }
+    Some(ret.join(" "))
+}
+
+fn follow_kmsg(sink: &Sink, dump: bool) -> io::Result<()> {
+    let mut kmsg = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/kmsg")?;
+    let since_boot = clock_gettime(ClockId::CLOCK_MONOTONIC)?;
+    let boot = SystemTime::now() - Duration::from(since_boot);
+
+    // Every read is one record
+    let mut buf = vec![0; 8192];
+    while !sink.stopped() {
+        match kmsg.read(&mut buf) {
+            Ok(n) => {
+                let record = String::from_utf8_lossy(&buf[..n]);
+                if let Some(entry) = parse_kmsg(&record, boot) {
+                    if !sink.send(entry) {
+                        break;
+                    }
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if dump {
+                    break;
+                }
+                thread::sleep(POLL);
+            },
+            // Overwritten before we got to it, carry on with the next one
+            Err(e) if e.raw_os_error() == Some(libc::EPIPE) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Like `tail -F`, reopening the file when it gets rotated or truncated.
+fn follow_file(path: &Path, sink: &Sink, dump: bool) -> io::Result<()> {
+    let (mut reader, mut ino) = open_tail(path)?;
+    let mut line = Vec::new();
+    while !sink.stopped() {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? > 0 && line.ends_with(b"\n") {
+            // Logs don't have to be UTF-8, like ones with binary junk in them
+            if !sink.send(parse_line(String::from_utf8_lossy(&line).trim_end())) {
+                break;
+            }
+            continue;
+        }
+        // Half a line, wait for the rest
+        reader.seek(SeekFrom::Current(-(line.len() as i64)))?;
+        if dump {
+            break;
+        }
+
+        thread::sleep(POLL);
+        let pos = reader.stream_position()?;
+        match path.metadata() {
+            Ok(meta) if meta.ino() != ino || meta.len() < pos => {
+                debug!("{:?} got rotated, reopening it", path);
+                reader = BufReader::new(File::open(path)?);
+                ino = meta.ino();
+            },
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Opens `path` at the first full line of its last [`TAIL_BYTES`].
+fn open_tail(path: &Path) -> io::Result<(BufReader<File>, u64)> {
+    let mut file = File::open(path)?;
+    let meta = file.metadata()?;
+    let mut reader = if meta.len() > TAIL_BYTES {
+        file.seek(SeekFrom::End(-(TAIL_BYTES as i64)))?;
+        let mut reader = BufReader::new(file);
+        reader.read_until(b'\n', &mut Vec::new())?;
+        reader
+    } else {
+        BufReader::new(file)
+    };
+    reader.fill_buf()?;
+    Ok((reader, meta.ino()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn filters_like_logcat() {
+        let filter = Filter::parse(["sshd:I", "cron:S", "*:W"]).unwrap();
+        assert!(filter.allows("sshd", Level::Info));
+        assert!(!filter.allows("sshd", Level::Debug));
+        assert!(!filter.allows("cron", Level::Fatal));
+        assert!(filter.allows("kernel", Level::Error));
+        assert!(!filter.allows("kernel", Level::Info));
+        assert!(Filter::parse(["sshd:X"]).is_err());
+        assert!(Filter::parse([]).unwrap().allows("any", Level::Verbose));
+    }
+
+    #[test]
+    fn parses_records() {
+        let boot = UNIX_EPOCH + Duration::from_secs(1000);
+        let entry = parse_kmsg("3,512,2500000,-;usb 1-1: device descriptor read error\n SUBSYSTEM=usb\n", boot).unwrap();
+        assert_eq!(entry.time, UNIX_EPOCH + Duration::from_millis(1_002_500));
+        assert_eq!(entry.level, Level::Error);
+        assert_eq!(entry.tag, "kernel");
+        assert_eq!(entry.message, "usb 1-1: device descriptor read error");
+        assert!(parse_kmsg("garbage", boot).is_none());
+
+        let entry = parse_line("Oct 18 23:44:02 board sshd[412]: Accepted key: RSA");
+        assert_eq!((entry.tag.as_str(), entry.message.as_str()), ("sshd", "Accepted key: RSA"));
+        let entry = parse_line("2026-10-18T23:44:02.123+00:00 board kernel: oops");
+        assert_eq!((entry.tag.as_str(), entry.message.as_str()), ("kernel", "oops"));
+        assert_eq!(parse_line("no tag here").tag, "syslog");
+    }
+
+    #[test]
+    fn takes_adb_logcat_from_the_shell() {
+        assert_eq!(from_shell(r#"export ANDROID_LOG_TAGS="''"; exec logcat '-d' 'sshd:I' '*:S'"#).as_deref(),
+                   Some("-d sshd:I *:S"));
+        assert_eq!(from_shell(r#"export ANDROID_LOG_TAGS="'*:W'" ; exec logcat"#).as_deref(), Some("*:W"));
+        assert_eq!(from_shell("logcat -d *:E").as_deref(), Some("-d *:E"));
+        assert_eq!(from_shell("logcat").as_deref(), Some(""));
+        assert_eq!(from_shell("logcat -d | grep usb"), None);
+        assert_eq!(from_shell("logcatx"), None);
+        assert_eq!(from_shell("ls; logcat"), None);
+        assert_eq!(from_shell("logcat $(id)"), None);
+    }
+
+    #[test]
+    fn dumps_files_that_arent_utf8() {
+        let path = std::env::temp_dir().join(format!("radbd-logcat-{}.log", std::process::id()));
+        std::fs::write(&path, b"Oct 18 23:44:02 board app[1]: bad \xff byte\nboard sshd[2]: ok\n").unwrap();
+        let cfg = LogcatConfig { kernel: false, files: vec![path.clone()] };
+        let (wake_tx, wake_rx) = crossbeam_channel::unbounded();
+        let mut svc = LogcatService::start("-d", &cfg, true, Waker::new(1, wake_tx)).unwrap();
+        wake_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(svc.is_done());
+
+        let packets: Vec<Vec<u8>> = svc.recv().try_iter().collect();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0][0], id::STDOUT);
+        let line = String::from_utf8(packets[0][5..].to_vec()).unwrap();
+        assert!(line.ends_with(" I app: bad \u{fffd} byte\n"), "{}", line);
+        assert!(String::from_utf8_lossy(&packets[1]).ends_with(" I sshd: ok\n"));
+        assert_eq!(packets[2], packet(id::EXIT, &[0]));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,31 +1,100 @@
 use std::io::Write;
-use std::env;
-use crossbeam_channel::Receiver;
-use std::collections::VecDeque;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::SystemTime;
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use log::debug;
+use nix::sys::signal::SigSet;
+use crate::config::{Config, Limits, LogcatConfig, ShellConfig};
+use crate::audit::{AuditLog, Outcome};
+use crate::policy::{Denied, Grant, Peer, Policy};
+use crate::privileges::Credentials;
 use crate::proto::{Message, CommandType};
 
+/// How many chunks of output a service may queue up before it has to wait
+/// for the host to read them.
+pub const OUTPUT_QUEUE_LEN: usize = 8;
+
 pub mod shell;
 pub mod sync;
-use shell::ShellService;
+pub mod reply;
+pub mod asciicast;
+pub mod logcat;
+pub mod framebuffer;
+pub mod reboot;
+pub mod remount;
+pub mod tcpip;
+pub mod bench;
+use shell::{Options, ShellService};
+use reply::ReplyService;
 use sync::SyncService;
+use logcat::LogcatService;
+use framebuffer::FramebufferService;
+use reboot::RebootService;
+use tcpip::{Mode, TcpipService};
+use bench::{SinkService, SourceService};
+
+/// Lets a service wake the main loop up for state changes that don't come
+/// with output, like its process exiting.
+#[derive(Debug, Clone)]
+pub struct Waker {
+    id: u32,
+    tx: Sender<u32>,
+}
+
+impl Waker {
+    pub fn new(id: u32, tx: Sender<u32>) -> Self {
+        Self { id, tx }
+    }
+    pub fn wake(&self) {
+        let _ = self.tx.send(self.id);
+    }
+    /// The local id of the stream this wakes up.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
 
 pub trait Service {
     fn handle_write(&mut self, data: Vec<u8>) -> Result<()>;
     fn recv(&mut self) -> &mut Receiver<Vec<u8>>;
     fn is_done(&mut self) -> bool;
+    /// Whether the service is still busy with earlier input, the host only
+    /// gets to send more once it isn't. Services that say so have to wake
+    /// the stream when they catch up.
+    fn input_full(&self) -> bool { false }
     fn close(&mut self) -> Result<()> { Ok(()) }
+    /// What actually runs, for the audit log.
+    fn command(&self) -> Option<String> { None }
+    /// Exit status of whatever ran, once it's done.
+    fn exit_status(&self) -> Option<i32> { None }
+    /// Calls `f` with the exit status once whatever ran is gone, which may
+    /// be a while after closing.
+    fn on_exit(&mut self, f: Box<dyn FnOnce(Option<i32>) + Send>) {
+        f(self.exit_status())
+    }
+}
+
+/// What the audit log needs to know once a stream closes.
+struct Audited {
+    log: Arc<AuditLog>,
+    service: String,
+    command: Option<String>,
+    peer: Peer,
+    start: SystemTime,
 }
 
 pub struct Stream {
     id: u32,
     remote_id: u32,
     svc: Box<dyn Service>,
-    pending_msgs: VecDeque<Message>,
     sent_ready: bool,
     ok_to_write: bool,
-    die: bool,
+    svc_eof: bool,
+    audit: Option<Audited>,
 }
 
 impl Stream {
@@ -34,47 +103,65 @@ impl Stream {
             id,
             remote_id,
             svc,
-            pending_msgs: VecDeque::new(),
             sent_ready: false,
             ok_to_write: true,
-            die: false,
+            svc_eof: false,
+            audit: None,
         }
     }
-    pub fn tick(&mut self, mut out: &mut impl Write) -> Result<bool> {
-        if !self.sent_ready {
-            Message::ready(self.id, self.remote_id).send_to(&mut out)?;
-            self.sent_ready = true;
-        }
-
-        for vec in self.svc.recv().try_iter() {
-            let msg = Message::write(self.id, self.remote_id, vec);
-            self.pending_msgs.push_back(msg);
+    pub fn remote_id(&self) -> u32 {
+        self.remote_id
+    }
+    /// The service's output channel, if the stream can take data from it
+    /// right now.
+    pub fn wants_output(&mut self) -> Option<&Receiver<Vec<u8>>> {
+        if self.ok_to_write && !self.svc_eof {
+            Some(self.svc.recv())
+        } else {
+            None
         }
+    }
+    pub fn tick(&mut self, mut out: &mut impl Write) -> Result<bool> {
+        self.send_ready(out)?;
 
-        if self.ok_to_write {
-            if let Some(msg) = self.pending_msgs.pop_front() {
-                msg.send_to(out)?;
-                self.ok_to_write = false;
+        // Output is only taken from the service when the host is ready for
+        // it, so a slow host makes the service block on its full queue.
+        if self.ok_to_write && !self.svc_eof {
+            match self.svc.recv().try_recv() {
+                Ok(vec) => {
+                    Message::write(self.id, self.remote_id, vec).send_to(&mut out)?;
+                    self.ok_to_write = false;
+                },
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => self.svc_eof = true,
             }
         }
+        // Taking output can be what makes room for more input
+        self.send_ready(out)?;
 
-        if self.svc.is_done() || self.die {
-            if self.pending_msgs.is_empty()  {
-                println!("Closing stream {}", self.id);
-                self.svc.close()?;
-                Message::close(self.id, self.remote_id).send_to(&mut out)?;
-                return Ok(true);
-            }
+        if self.svc.is_done() && self.svc.recv().is_empty() {
+            debug!("Closing stream {}", self.id);
+            self.svc.close()?;
+            self.audit_close();
+            Message::close(self.id, self.remote_id).send_to(&mut out)?;
+            return Ok(true);
         }
 
         Ok(false)
     }
+    /// Holding back the ack stops the host from sending more until the
+    /// service caught up with its input.
+    fn send_ready(&mut self, out: &mut impl Write) -> Result<()> {
+        if !self.sent_ready && !self.svc.input_full() {
+            Message::ready(self.id, self.remote_id).send_to(out)?;
+            self.sent_ready = true;
+        }
+        Ok(())
+    }
     pub fn handle_msg(&mut self, msg: Message) -> Result<()> {
         match msg.meta().cmd() {
-            CommandType::Ready{remote_id, ..} => {
-                if self.id == *remote_id {
-                    self.ok_to_write = true;
-                }
+            CommandType::Ready{remote_id, ..} if self.id == *remote_id => {
+                self.ok_to_write = true;
             },
             CommandType::Write{..} => {
                 let data = msg.data().to_vec();
@@ -85,27 +172,314 @@ impl Stream {
         }
         Ok(())
     }
-    pub fn schedule_death(&mut self) {
-        self.die = true;
+    /// Closes the service without telling the host.
+    pub fn close(mut self) -> Result<()> {
+        debug!("Closing stream {}", self.id);
+        let ret = self.svc.close();
+        self.audit_close();
+        ret
+    }
+    fn audit_close(&mut self) {
+        if let Some(a) = self.audit.take() {
+            let id = self.id;
+            self.svc.on_exit(Box::new(move |status| {
+                a.log.close_stream(id, &a.service, a.command.as_deref(), &a.peer, a.start, status);
+            }));
+        }
     }
 }
 
-pub fn spawn(id: u32, remote_id: u32, which: String) -> Result<Stream> {
-    let which = which.trim_matches('\0');
-    let split = which.split(':');
-    let vec: Vec<&str> = split.collect();
-    let name = vec.get(0).unwrap();
-    let arg = vec.get(1).unwrap();
+/// Undoes radbd blocking the signals that ask it to quit for what `cmd`
+/// runs, a blocked mask outlives exec.
+pub(crate) fn unblock_signals(cmd: &mut Command) {
+    unsafe {
+        cmd.pre_exec(|| Ok(SigSet::all().thread_unblock()?));
+    }
+}
 
-    let ret = match *name {
-        "shell" => if arg == &"" {
-            ShellService::start(env::var("SHELL").unwrap_or("sh".to_string()))?
-        } else {
-            ShellService::start(arg.to_string())?
-        },
-        "sync" => SyncService::start()?,
-        _ => todo!("{:?}", which),
-    };
+/// Creates a service from whatever follows its prefix in the OPEN string.
+pub type Factory = Box<dyn Fn(&str, &Grant, Waker) -> Result<Box<dyn Service>> + Send + Sync>;
+
+struct Entry {
+    prefix: String,
+    features: Vec<String>,
+    factory: Factory,
+}
+
+/// Maps OPEN service strings to services, like `shell:` or `sync:`.
+pub struct Registry {
+    entries: Vec<Entry>,
+    policy: Policy,
+    audit: Option<Arc<AuditLog>>,
+}
 
-    Ok(Stream::new(id, remote_id, ret))
+impl Registry {
+    /// A registry without any services.
+    pub fn empty() -> Self {
+        Self {
+            entries: Vec::new(),
+            policy: Policy::default(),
+            audit: None,
+        }
+    }
+    /// Registers a service for OPEN strings starting with `prefix`, the
+    /// features are advertised to the host in the connection banner.
+    ///
+    /// The longest matching prefix wins, so a later registration can take
+    /// over a more specific form of an existing service.
+    pub fn register<F>(&mut self, prefix: &str, features: &[&str], factory: F) -> &mut Self
+    where
+        F: Fn(&str, &Grant, Waker) -> Result<Box<dyn Service>> + Send + Sync + 'static,
+    {
+        self.entries.retain(|e| e.prefix != prefix);
+        self.entries.push(Entry {
+            prefix: prefix.to_string(),
+            features: features.iter().map(|f| f.to_string()).collect(),
+            factory: Box::new(factory),
+        });
+        self
+    }
+    /// Replaces the policy every OPEN gets checked against.
+    pub fn set_policy(&mut self, policy: Policy) -> &mut Self {
+        self.policy = policy;
+        self
+    }
+    /// Records every OPEN and how its stream ended from now on.
+    pub fn set_audit(&mut self, audit: AuditLog) -> &mut Self {
+        self.audit = Some(Arc::new(audit));
+        self
+    }
+    pub fn features(&self) -> Vec<&str> {
+        let mut ret: Vec<&str> = self.entries.iter()
+            .flat_map(|e| e.features.iter().map(String::as_str))
+            .collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+    /// Starts the service named by an OPEN payload, None if nothing handles it.
+    ///
+    /// Fails with [`crate::policy::Denied`] if the policy doesn't let `peer`
+    /// open it.
+    pub fn spawn(&self, id: u32, remote_id: u32, which: &str, peer: &Peer, waker: Waker) -> Result<Option<Stream>> {
+        let which = which.trim_end_matches('\0');
+        let ret = self.start(which, peer, waker);
+
+        let Some(log) = &self.audit else {
+            return ret.map(|svc| svc.map(|svc| Stream::new(id, remote_id, svc)));
+        };
+        let (outcome, command) = match &ret {
+            Ok(Some(svc)) => (Outcome::Started, svc.command()),
+            Ok(None) => (Outcome::Unknown, None),
+            Err(e) if e.is::<Denied>() => (Outcome::Denied, None),
+            Err(_) => (Outcome::Failed, None),
+        };
+        log.open_stream(id, which, command.as_deref(), peer, outcome);
+
+        let Some(svc) = ret? else { return Ok(None); };
+        let mut stream = Stream::new(id, remote_id, svc);
+        stream.audit = Some(Audited {
+            log: log.clone(),
+            service: which.to_string(),
+            command,
+            peer: peer.clone(),
+            start: SystemTime::now(),
+        });
+        Ok(Some(stream))
+    }
+    fn start(&self, which: &str, peer: &Peer, waker: Waker) -> Result<Option<Box<dyn Service>>> {
+        let Some(entry) = self.entries.iter()
+            .filter(|e| which.starts_with(&e.prefix))
+            .max_by_key(|e| e.prefix.len()) else {
+            return Ok(None);
+        };
+
+        let grant = self.policy.check(&policy_name(which), peer)?;
+        Ok(Some((entry.factory)(&which[entry.prefix.len()..], &grant, waker)?))
+    }
+}
+
+/// What the policy sees an OPEN as, shell v2 options and `exec:` run the
+/// same shell, so rules about `shell:` cover those too.
+fn policy_name(which: &str) -> String {
+    if let Some(arg) = which.strip_prefix("shell,") {
+        return format!("shell:{}", arg.split_once(':').map_or("", |(_, cmd)| cmd));
+    }
+    match which.strip_prefix("exec:") {
+        Some(cmd) => format!("shell:{}", cmd),
+        None => which.to_string(),
+    }
+}
+
+impl Registry {
+    /// The services radbd ships with, as far as the config enables them.
+    pub fn builtin(cfg: &Config) -> Result<Self> {
+        let mut ret = Self::empty();
+        ret.set_policy(cfg.policy.clone());
+        let enabled = |name: &str| cfg.services.enabled.iter().any(|s| s == name);
+        let privs = Arc::new(cfg.privileges.privileges()?);
+        let logcat = enabled("logcat").then(|| cfg.logcat.clone());
+
+        if enabled("shell") {
+            let (shell, privs_, logcat_) = (cfg.shell.clone(), privs.clone(), logcat.clone());
+            let limits = cfg.limits.get("shell").copied().unwrap_or_default();
+            ret.register("shell:", &[], move |arg, _, waker| {
+                let opts = Options {
+                    command: (!arg.is_empty()).then(|| arg.to_string()),
+                    pty: true,
+                    ..Options::default()
+                };
+                start_shell(opts, &shell, &limits, privs_.current(), logcat_.as_ref(), waker)
+            });
+            let shell = cfg.shell.clone();
+            let (privs, logcat) = (privs.clone(), logcat.clone());
+            ret.register("shell,", &["shell_v2"], move |arg, _, waker| {
+                start_shell(Options::parse(arg), &shell, &limits, privs.current(), logcat.as_ref(), waker)
+            });
+        }
+        if enabled("exec") {
+            let shell = cfg.shell.clone();
+            let (privs, logcat) = (privs.clone(), logcat.clone());
+            let limits = cfg.limits.get("exec").copied().unwrap_or_default();
+            ret.register("exec:", &[], move |arg, _, waker| {
+                let opts = Options {
+                    command: Some(arg.to_string()),
+                    ..Options::default()
+                };
+                start_shell(opts, &shell, &limits, privs.current(), logcat.as_ref(), waker)
+            });
+        }
+        if enabled("sync") {
+            let roots = cfg.sync.roots.clone();
+            let privs = privs.clone();
+            let remount = enabled("remount");
+            ret.register("sync:", &[], move |_, grant, _| {
+                SyncService::start(roots.clone(), grant.paths.map(<[_]>::to_vec), privs.current().cloned(), remount)
+            });
+        }
+        if let Some(logcat) = logcat {
+            ret.register("logcat:", &[], move |arg, _, waker| LogcatService::start(arg, &logcat, false, waker));
+        }
+        if enabled("framebuffer") {
+            let framebuffer = cfg.framebuffer.clone();
+            ret.register("framebuffer:", &[], move |_, _, _| FramebufferService::start(&framebuffer));
+        }
+        if enabled("reboot") {
+            let targets = cfg.reboot.targets.clone();
+            let perform: reboot::Performer = Arc::new(reboot::perform);
+            ret.register("reboot:", &[], move |target, _, waker| {
+                RebootService::start(target, targets.get(target), perform.clone(), waker)
+            });
+        }
+        if enabled("remount") {
+            let remount = cfg.remount.clone();
+            let privs = privs.clone();
+            ret.register("remount:", &[], move |_, _, _| match privs.current() {
+                Some(_) => ReplyService::start("Not running as root. Try \"adb root\" first.\n"),
+                None => ReplyService::start(remount::remount(&remount)),
+            });
+        }
+        if enabled("tcpip") {
+            let state = cfg.tcp.state.clone().context("tcpip needs `tcp.state`")?;
+            let restart: tcpip::Restarter = Arc::new(tcpip::restart);
+            let (state_, restart_) = (state.clone(), restart.clone());
+            ret.register("tcpip:", &[], move |arg, _, waker| match arg.parse() {
+                Ok(port) if port != 0 => TcpipService::start(Mode::Tcp(port), &state_, restart_.clone(), waker),
+                _ => ReplyService::start(format!("invalid port {:?}\n", arg)),
+            });
+            let usb = cfg.usb.enabled;
+            ret.register("usb:", &[], move |_, _, waker| if usb {
+                TcpipService::start(Mode::Usb, &state, restart.clone(), waker)
+            } else {
+                ReplyService::start("radbd has no USB transport to go back to\n")
+            });
+        }
+        if enabled("bench") {
+            ret.register("sink:", &[], |arg, _, waker| SinkService::start(arg, waker));
+            ret.register("source:", &[], |arg, _, waker| SourceService::start(arg, waker));
+        }
+        if enabled("root") {
+            let privs_ = privs.clone();
+            ret.register("root:", &[], move |_, _, _| ReplyService::start(privs_.root()));
+            ret.register("unroot:", &[], move |_, _, _| ReplyService::start(privs.unroot()));
+        }
+        Ok(ret)
+    }
+}
+
+/// Runs `opts` in a shell, unless it's the logcat command `adb logcat` sends
+/// and `logcat` is there to take it.
+fn start_shell(opts: Options, shell: &ShellConfig, limits: &Limits, creds: Option<&Credentials>,
+               logcat: Option<&LogcatConfig>, waker: Waker) -> Result<Box<dyn Service>> {
+    if let (Some(cfg), Some(args)) = (logcat, opts.command.as_deref().and_then(logcat::from_shell)) {
+        return LogcatService::start(&args, cfg, opts.v2, waker);
+    }
+    ShellService::start(opts, shell, limits, creds, waker)
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::builtin(&Config::default())
+            .expect("The default config needs no lookups")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn shell_rules_cover_every_way_in() {
+        assert_eq!(policy_name("shell,v2,raw:ls -l"), "shell:ls -l");
+        assert_eq!(policy_name("shell,v2,pty:"), "shell:");
+        assert_eq!(policy_name("exec:cat /x"), "shell:cat /x");
+        assert_eq!(policy_name("sync:"), "sync:");
+
+        let mut cfg = Config::default();
+        cfg.services.enabled.push("exec".to_string());
+        cfg.policy = toml::from_str(r#"
+            [[rules]]
+            services = ["shell:*rm*"]
+            action = "deny"
+        "#).unwrap();
+        let registry = Registry::builtin(&cfg).unwrap();
+        let (wake_tx, _wake_rx) = crossbeam_channel::unbounded();
+        let peer = Peer::new("usb");
+        for which in ["shell:rm -rf /", "shell,v2,raw:rm -rf /", "exec:rm -rf /"] {
+            let err = registry.spawn(1, 1, which, &peer, Waker::new(1, wake_tx.clone())).err();
+            assert!(err.is_some_and(|e| e.is::<Denied>()), "{}", which);
+        }
+    }
+
+    #[test]
+    fn audits_the_exit_status_when_the_host_closes_first() {
+        let path = std::env::temp_dir().join(format!("radbd-audit-close-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut cfg = Config::default();
+        cfg.services.enabled.push("exec".to_string());
+        let mut registry = Registry::builtin(&cfg).unwrap();
+        registry.set_audit(AuditLog::open(&path, 1 << 20, 0).unwrap());
+
+        // Shrugs off SIGHUP, so it takes SIGKILL a second later
+        let (wake_tx, _wake_rx) = crossbeam_channel::unbounded();
+        let stream = registry.spawn(1, 1, "exec:trap '' HUP; sleep 5", &Peer::new("tcp"), Waker::new(1, wake_tx))
+            .unwrap().unwrap();
+        let started = Instant::now();
+        stream.close().unwrap();
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        let close = loop {
+            let log = std::fs::read_to_string(&path).unwrap();
+            if let Some(close) = log.lines().nth(1) {
+                break close.to_string();
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "no close record");
+            thread::sleep(Duration::from_millis(20));
+        };
+        assert!(close.contains(r#""event":"close","stream":1,"#), "{}", close);
+        assert!(close.ends_with(r#","exit_status":137}"#), "{}", close);
+        std::fs::remove_file(path).unwrap();
+    }
 }
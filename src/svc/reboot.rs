@@ -0,0 +1,139 @@
+//! `reboot:TARGET`, for `adb reboot`, `adb reboot bootloader` and friends.
+
+use std::ffi::CString;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use crossbeam_channel::Receiver;
+use log::{error, info};
+use nix::sys::reboot::{reboot, RebootMode};
+use nix::unistd;
+use crate::config::RebootAction;
+use crate::svc::{unblock_signals, Service, Waker};
+
+/// Gives the stream's OKAY and CLSE a chance to reach the host first.
+const DELAY: Duration = Duration::from_millis(500);
+
+/// Carries out a reboot action, [`perform`] outside of tests.
+pub type Performer = Arc<dyn Fn(&RebootAction) -> Result<()> + Send + Sync>;
+
+pub struct RebootService {
+    rx: Receiver<Vec<u8>>,
+    done: Arc<AtomicBool>,
+}
+
+impl Service for RebootService {
+    fn handle_write(&mut self, _data: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+    fn recv(&mut self) -> &mut Receiver<Vec<u8>> {
+        &mut self.rx
+    }
+    /// Done once the action failed or a hook returned, a reboot that worked
+    /// takes the stream with it.
+    fn is_done(&mut self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+}
+
+impl RebootService {
+    pub fn start(target: &str, action: Option<&RebootAction>, perform: Performer,
+                 waker: Waker) -> Result<Box<dyn Service>> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let done = Arc::new(AtomicBool::new(false));
+        match action {
+            None => {
+                tx.send(format!("reboot: unknown target {:?}\n", target).into_bytes())?;
+                done.store(true, Ordering::SeqCst);
+            },
+            Some(action) => {
+                info!("Rebooting to {:?}: {:?}", target, action);
+                let (action, done) = (action.clone(), done.clone());
+                thread::spawn(move || {
+                    // Flushing can take a while, the connection goes on meanwhile
+                    unistd::sync();
+                    thread::sleep(DELAY);
+                    if let Err(e) = perform(&action) {
+                        error!("Failed to reboot: {:#}", e);
+                        let _ = tx.send(format!("reboot failed: {:#}\n", e).into_bytes());
+                    }
+                    done.store(true, Ordering::SeqCst);
+                    waker.wake();
+                });
+            },
+        }
+        Ok(Box::new(Self { rx, done }))
+    }
+}
+
+/// Actually reboots, or runs the hook that does.
+pub fn perform(action: &RebootAction) -> Result<()> {
+    match action {
+        RebootAction::Restart(arg) if arg.is_empty() => {
+            reboot(RebootMode::RB_AUTOBOOT)?;
+        },
+        RebootAction::Restart(arg) => {
+            // What reboot(2) calls LINUX_REBOOT_CMD_RESTART2, the argument
+            // goes to the bootloader
+            let arg = CString::new(arg.as_str())?;
+            let ret = unsafe {
+                libc::syscall(libc::SYS_reboot, libc::LINUX_REBOOT_MAGIC1, libc::LINUX_REBOOT_MAGIC2,
+                              libc::LINUX_REBOOT_CMD_RESTART2, arg.as_ptr())
+            };
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error()).context("reboot(2)");
+            }
+        },
+        RebootAction::PowerOff => {
+            reboot(RebootMode::RB_POWER_OFF)?;
+        },
+        RebootAction::Command(argv) => {
+            let Some((prog, args)) = argv.split_first() else { bail!("Empty reboot command"); };
+            let mut cmd = Command::new(prog);
+            unblock_signals(&mut cmd);
+            let status = cmd.args(args).status()
+                .with_context(|| format!("Failed to run {:?}", prog))?;
+            if !status.success() {
+                bail!("{:?} failed with {}", prog, status);
+            }
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn run(target: &str, action: Option<RebootAction>, fail: bool) -> (Vec<RebootAction>, String) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let calls_ = calls.clone();
+        let perform: Performer = Arc::new(move |action| {
+            calls_.lock().unwrap().push(action.clone());
+            if fail { bail!("Operation not permitted") } else { Ok(()) }
+        });
+        let (wake_tx, wake_rx) = crossbeam_channel::unbounded();
+        let mut svc = RebootService::start(target, action.as_ref(), perform, Waker::new(1, wake_tx)).unwrap();
+
+        if action.is_some() {
+            wake_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        let out: Vec<u8> = svc.recv().try_iter().flatten().collect();
+        assert!(svc.is_done());
+        let calls = calls.lock().unwrap().clone();
+        (calls, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn performs_the_target_action() {
+        let action = RebootAction::Restart("bootloader".to_string());
+        assert_eq!(run("bootloader", Some(action.clone()), false), (vec![action.clone()], String::new()));
+        assert_eq!(run("bootloader", Some(action.clone()), true),
+                   (vec![action], "reboot failed: Operation not permitted\n".to_string()));
+        assert_eq!(run("sideload", None, false), (vec![], "reboot: unknown target \"sideload\"\n".to_string()));
+    }
+}
@@ -0,0 +1,146 @@
+//! `remount:`, for `adb remount` on images that mount / read-only.
+
+use std::fmt::Write;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use log::info;
+use nix::errno::Errno;
+use nix::mount::{self, MsFlags};
+use nix::sys::statvfs::{statvfs, FsFlags};
+use crate::config::RemountConfig;
+
+/// The flags a remount has to repeat to keep them, statvfs(3) reports them
+/// with the same values as mount(2) takes.
+const KEPT: MsFlags = MsFlags::MS_NOSUID.union(MsFlags::MS_NODEV).union(MsFlags::MS_NOEXEC)
+    .union(MsFlags::MS_SYNCHRONOUS).union(MsFlags::MS_MANDLOCK).union(MsFlags::MS_NOATIME)
+    .union(MsFlags::MS_NODIRATIME).union(MsFlags::MS_RELATIME);
+
+/// Makes every configured mount point writable and says how, like adbd the
+/// last line tells whether all of them are.
+pub fn remount(cfg: &RemountConfig) -> String {
+    let mut ret = String::new();
+    let mut ok = true;
+    for mount in &cfg.mounts {
+        match remount_one(mount, cfg.overlay.as_deref()) {
+            Ok(done) => {
+                info!("Remount of {}: {}", mount.display(), done);
+                let _ = writeln!(ret, "{}: {}", mount.display(), done);
+            },
+            Err(e) => {
+                ok = false;
+                let _ = writeln!(ret, "{}: {:#}", mount.display(), e);
+            },
+        }
+    }
+    ret.push_str(if ok { "remount succeeded\n" } else { "remount failed\n" });
+    ret
+}
+
+fn remount_one(mount: &Path, overlay: Option<&Path>) -> Result<String> {
+    let flags = writable(mount)?;
+    let Some(flags) = flags else {
+        return Ok("already writable".to_string());
+    };
+
+    match remount_rw(mount, flags) {
+        Ok(()) => return Ok("remounted read-write".to_string()),
+        Err(Errno::EPERM) => bail!("not permitted, radbd has to run as root"),
+        // The device underneath is read-only, an overlay is all that's left
+        Err(Errno::EACCES | Errno::EROFS) => (),
+        Err(e) => return Err(e).context("remount failed"),
+    }
+    let Some(overlay) = overlay else {
+        bail!("the device is read-only, `remount.overlay` would allow an overlay");
+    };
+
+    // Lookups of / itself don't see what's mounted on top of it, so / gets
+    // an overlay for each directory on the same filesystem instead
+    let targets = if mount == Path::new("/") { top_dirs()? } else { vec![mount.to_path_buf()] };
+    let mut done = Vec::new();
+    for target in targets {
+        let dir = overlay.join(target.strip_prefix("/").unwrap_or(&target));
+        let (upper, work) = (dir.join("upper"), dir.join("work"));
+        fs::create_dir_all(&upper)
+            .and_then(|()| fs::create_dir_all(&work))
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        let opts = overlay_options(&target, &upper, &work)?;
+        mount::mount(Some("overlay"), &target, Some("overlay"), MsFlags::empty(), Some(opts.as_str()))
+            .with_context(|| format!("overlay on {} failed", target.display()))?;
+        done.push(target.display().to_string());
+    }
+    if done.is_empty() {
+        return Ok("already overlaid".to_string());
+    }
+    Ok(format!("overlaid {}, changes go to {}", done.join(", "), overlay.display()))
+}
+
+/// The flags to remount with, None if `mount` is writable already.
+fn writable(mount: &Path) -> Result<Option<MsFlags>> {
+    let stat = statvfs(mount)
+        .with_context(|| format!("statvfs({})", mount.display()))?;
+    if !stat.flags().contains(FsFlags::ST_RDONLY) {
+        return Ok(None);
+    }
+    Ok(Some(MsFlags::from_bits_truncate(stat.flags().bits()) & KEPT))
+}
+
+fn remount_rw(mount: &Path, flags: MsFlags) -> nix::Result<()> {
+    mount::mount(None::<&str>, mount, None::<&str>, MsFlags::MS_REMOUNT | flags, None::<&str>)
+}
+
+/// The directories right under / on its own filesystem, ones with
+/// something mounted on them already are left alone.
+fn top_dirs() -> Result<Vec<PathBuf>> {
+    let root = fs::metadata("/")?.dev();
+    let mut ret = Vec::new();
+    for entry in fs::read_dir("/")? {
+        let entry = entry?;
+        let meta = fs::symlink_metadata(entry.path())?;
+        if meta.is_dir() && meta.dev() == root {
+            ret.push(entry.path());
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+fn overlay_options(lower: &Path, upper: &Path, work: &Path) -> Result<String> {
+    let mut ret = String::new();
+    for (key, path) in [("lowerdir", lower), ("upperdir", upper), ("workdir", work)] {
+        let path = path.to_str()
+            .with_context(|| format!("{} isn't UTF-8", path.display()))?;
+        if path.contains([',', ':']) {
+            bail!("{} can't be part of an overlay, it contains ',' or ':'", path);
+        }
+        if !ret.is_empty() {
+            ret.push(',');
+        }
+        let _ = write!(ret, "{}={}", key, path);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_each_mount() {
+        let dir = std::env::temp_dir();
+        let cfg = RemountConfig {
+            mounts: vec![dir.clone(), PathBuf::from("/nonexistent-radbd")],
+            overlay: None,
+        };
+        let out = remount(&cfg);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], format!("{}: already writable", dir.display()));
+        assert!(lines[1].starts_with("/nonexistent-radbd: statvfs"), "{}", out);
+        assert_eq!(lines[2], "remount failed");
+
+        assert_eq!(overlay_options(Path::new("/usr"), Path::new("/data/o/usr/upper"), Path::new("/data/o/usr/work")).unwrap(),
+                   "lowerdir=/usr,upperdir=/data/o/usr/upper,workdir=/data/o/usr/work");
+        assert!(overlay_options(Path::new("/a:b"), Path::new("/u"), Path::new("/w")).is_err());
+    }
+}
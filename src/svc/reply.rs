@@ -0,0 +1,29 @@
+use crossbeam_channel::Receiver;
+use anyhow::Result;
+use crate::svc::Service;
+
+/// Sends one fixed reply and closes, for services like `root:` that only
+/// report what they did.
+pub struct ReplyService {
+    rx: Receiver<Vec<u8>>,
+}
+
+impl Service for ReplyService {
+    fn handle_write(&mut self, _data: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+    fn recv(&mut self) -> &mut Receiver<Vec<u8>> {
+        &mut self.rx
+    }
+    fn is_done(&mut self) -> bool {
+        true
+    }
+}
+
+impl ReplyService {
+    pub fn start(reply: impl Into<Vec<u8>>) -> Result<Box<dyn Service>> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        tx.send(reply.into())?;
+        Ok(Box::new(Self { rx }))
+    }
+}
@@ -1,78 +1,661 @@
-use std::thread;
-use std::io::{Read, Write};
+use std::thread::{self, JoinHandle};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use crate::cgroup::Cgroup;
 use crate::proto::MAXDATA;
-use crate::svc::Service;
-use crossbeam_channel::{Sender, Receiver};
-use portable_pty::{Child, native_pty_system, PtySize, CommandBuilder};
-use anyhow::Result;
+use crate::privileges::Credentials;
+use crate::svc::{unblock_signals, Service, Waker, OUTPUT_QUEUE_LEN};
+use crate::svc::asciicast::Recorder;
+use crossbeam_channel::{Sender, Receiver, RecvTimeoutError};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::pty::{openpty, Winsize};
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{getuid, pipe2, Pid, User};
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use crate::config::{Limits, ShellConfig};
+
+/// What to run and how the host talks to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Run with `-c`, a login shell if None.
+    pub command: Option<String>,
+    /// On a pty, or with plain pipes for stdout and stderr.
+    pub pty: bool,
+    /// Shell protocol v2, with stdout, stderr and the exit status in
+    /// separate packets.
+    pub v2: bool,
+    /// `TERM` asked for by the host.
+    pub term: Option<String>,
+}
+
+impl Options {
+    /// Parses what follows `shell,` in a v2 OPEN, like
+    /// `v2,TERM=xterm-256color,pty:ls`.
+    pub fn parse(arg: &str) -> Self {
+        let (args, cmd) = arg.split_once(':').unwrap_or((arg, ""));
+        let mut ret = Self {
+            command: (!cmd.is_empty()).then(|| cmd.to_string()),
+            ..Self::default()
+        };
+        // Like adbd, a pty unless asked otherwise only without a command
+        ret.pty = ret.command.is_none();
+        for arg in args.split(',') {
+            match arg {
+                "v2" => ret.v2 = true,
+                "pty" => ret.pty = true,
+                "raw" => ret.pty = false,
+                _ => if let Some(term) = arg.strip_prefix("TERM=") {
+                    ret.term = Some(term.to_string());
+                },
+            }
+        }
+        ret
+    }
+}
+
+/// Packet ids of shell protocol v2.
+pub(crate) mod id {
+    pub const STDIN: u8 = 0;
+    pub const STDOUT: u8 = 1;
+    pub const STDERR: u8 = 2;
+    pub const EXIT: u8 = 3;
+    pub const CLOSE_STDIN: u8 = 4;
+    pub const WINDOW_SIZE: u8 = 5;
+}
+pub(crate) const HEADER_LEN: usize = 5;
+
+const WINDOW: (u16, u16) = (80, 24);
+/// What passwd means by an empty shell field.
+const DEFAULT_SHELL: &str = "/bin/sh";
+/// Exit status of a session killed for running past its timeout.
+const TIMED_OUT: i32 = 124;
+/// How long output from a pty may stall after the shell exited before its
+/// exit status goes out regardless.
+const PTY_LINGER: Duration = Duration::from_millis(100);
+/// How long a session gets to exit after SIGHUP, before SIGKILL.
+const HANGUP_GRACE: Duration = Duration::from_secs(1);
+/// How many chunks from the host may wait for the child to read them
+/// before the host has to.
+const STDIN_QUEUE_LEN: usize = 4;
 
 pub struct ShellService {
     rx: Receiver<Vec<u8>>,
-    child_stdin: Box<dyn Write>,
-    child: Box<dyn Child>,
+    command: String,
+    v2: bool,
+    /// To the thread writing the child's stdin, dropping it closes that.
+    stdin: Option<Sender<Vec<u8>>>,
+    pty: Option<File>,
+    /// Incomplete v2 packet from the host.
+    input: Vec<u8>,
+    /// Also the session id and process group, the child leads both.
+    pid: Pid,
+    /// Set once the child got reaped.
+    status: Arc<Mutex<Option<i32>>>,
+    /// Set once its output and exit status are queued, too.
+    done: Arc<AtomicBool>,
+    /// Disconnects once the child got reaped.
+    exited: Receiver<()>,
+    waiter: Option<JoinHandle<()>>,
+    cgroup: Option<Cgroup>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
 }
 
 impl Service for ShellService {
     fn handle_write(&mut self, data: Vec<u8>) -> Result<()> {
-        self.child_stdin.write_all(&data)?;
+        if !self.v2 {
+            return self.write_stdin(&data);
+        }
+
+        self.input.extend(data);
+        while self.input.len() >= HEADER_LEN {
+            let len = u32::from_le_bytes(self.input[1..HEADER_LEN].try_into().unwrap()) as usize;
+            if self.input.len() < HEADER_LEN + len {
+                break;
+            }
+            let packet: Vec<u8> = self.input.drain(..HEADER_LEN + len).collect();
+            self.handle_packet(packet[0], &packet[HEADER_LEN..])?;
+        }
         Ok(())
     }
-    #[allow(unused_must_use)]
+    /// Hangs up on the whole session, background jobs included, and leaves
+    /// killing what's left to a thread.
     fn close(&mut self) -> Result<()> {
-        self.child.kill();
-        self.child.wait()?;
+        signal_session(self.pid, Signal::SIGHUP);
+
+        let pid = self.pid;
+        let exited = self.exited.clone();
+        let waiter = self.waiter.take();
+        let cgroup = self.cgroup.take();
+        thread::spawn(move || {
+            let _ = exited.recv_timeout(HANGUP_GRACE);
+            signal_session(pid, Signal::SIGKILL);
+            if let Some(cgroup) = cgroup {
+                cgroup.destroy();
+            }
+            if let Some(waiter) = waiter {
+                let _ = waiter.join();
+            }
+        });
         Ok(())
     }
     fn recv(&mut self) -> &mut Receiver<Vec<u8>> {
         &mut self.rx
     }
     fn is_done(&mut self) -> bool {
-        if let Ok(result) = self.child.try_wait() {
-            result.is_some()
-        } else {
-            true
+        self.done.load(Ordering::SeqCst)
+    }
+    fn input_full(&self) -> bool {
+        self.stdin.as_ref().is_some_and(|tx| tx.len() >= STDIN_QUEUE_LEN)
+    }
+    fn command(&self) -> Option<String> {
+        Some(self.command.clone())
+    }
+    fn exit_status(&self) -> Option<i32> {
+        *self.status.lock().unwrap()
+    }
+    /// Waits for the child on a thread of its own if it's still around, like
+    /// after the host hung up on it.
+    fn on_exit(&mut self, f: Box<dyn FnOnce(Option<i32>) + Send>) {
+        if let Some(status) = self.exit_status() {
+            return f(Some(status));
         }
+        let (exited, status) = (self.exited.clone(), self.status.clone());
+        thread::spawn(move || {
+            let _ = exited.recv();
+            f(*status.lock().unwrap())
+        });
     }
 }
 
 impl ShellService {
-    pub fn start(cmd_args: String) -> Result<Box<dyn Service>> {
-        let (tx, rx) = crossbeam_channel::unbounded();
+    /// Runs the user's shell, see [`Options`].
+    pub fn start(opts: Options, cfg: &ShellConfig, limits: &Limits, creds: Option<&Credentials>,
+                 waker: Waker) -> Result<Box<dyn Service>> {
+        let (tx, rx) = crossbeam_channel::bounded(OUTPUT_QUEUE_LEN);
 
-        let pair = native_pty_system().openpty(PtySize {
-            rows: 24,
-            cols: 80,
-            pixel_width: 0,
-            pixel_height: 0
-        })?;
+        let user = match &cfg.user {
+            Some(name) => User::from_name(name)?
+                .with_context(|| format!("No such user {:?}", name))?,
+            None => match creds {
+                Some(creds) => creds.user.clone(),
+                None => User::from_uid(getuid())?
+                    .context("radbd's own user isn't in passwd")?,
+            },
+        };
+        let shell = login_shell(cfg, &user);
 
-        let mut cmd = CommandBuilder::new("bash");
-        cmd.arg("-c");
-        cmd.arg(cmd_args);
+        let mut cmd = Command::new(&shell);
+        unblock_signals(&mut cmd);
+        let command = match &opts.command {
+            None => {
+                // A leading dash is how login(1) asks for a login shell
+                let name = shell.rsplit('/').next().unwrap_or(&shell);
+                cmd.arg0(format!("-{}", name));
+                format!("{} (login)", shell)
+            },
+            Some(cmd_args) => {
+                cmd.arg("-c");
+                cmd.arg(cmd_args);
+                format!("{} -c {:?}", shell, cmd_args)
+            },
+        };
 
-        let child = pair.slave.spawn_command(cmd)?;
-        let child_stdin = pair.master.take_writer().unwrap();
-        let child_stdout = pair.master.try_clone_reader().unwrap();
+        cmd.env_clear();
+        cmd.env("HOME", &user.dir);
+        cmd.env("USER", &user.name);
+        cmd.env("LOGNAME", &user.name);
+        cmd.env("SHELL", &shell);
+        cmd.env("PATH", &cfg.path);
+        if opts.pty {
+            cmd.env("TERM", opts.term.as_deref().unwrap_or(&cfg.term));
+        }
+        // Service accounts tend to have a home that doesn't exist
+        cmd.current_dir(if user.dir.is_dir() { user.dir.as_path() } else { Path::new("/") });
 
-        thread::spawn(|| cp_stream_to_chan(child_stdout, tx));
+        // Each gets a session of its own, so closing it can take down
+        // everything it started, job control or not
+        let (pty, stdout, stderr) = if opts.pty {
+            let size = Winsize { ws_row: WINDOW.1, ws_col: WINDOW.0, ws_xpixel: 0, ws_ypixel: 0 };
+            let pair = openpty(&size, None)?;
+            for fd in [pair.master, pair.slave] {
+                fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+            }
+            let (master, slave) = unsafe { (File::from_raw_fd(pair.master), File::from_raw_fd(pair.slave)) };
+            cmd.stdin(Stdio::from(slave.try_clone()?));
+            cmd.stdout(Stdio::from(slave.try_clone()?));
+            cmd.stderr(Stdio::from(slave));
+            unsafe {
+                cmd.pre_exec(|| {
+                    // Make the pty the controlling terminal, for job control
+                    // and ^C
+                    if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            (Some(master.try_clone()?), master, None)
+        } else {
+            let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+            let (read, write) = unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) };
+            cmd.stdin(Stdio::piped());
+            cmd.stdout(Stdio::from(write.try_clone()?));
+            // Only v2 can tell the host which is which
+            let stderr = if opts.v2 {
+                let (err_read, err_write) = pipe2(OFlag::O_CLOEXEC)?;
+                let (err_read, err_write) = unsafe { (File::from_raw_fd(err_read), File::from_raw_fd(err_write)) };
+                cmd.stderr(Stdio::from(err_write));
+                Some(err_read)
+            } else {
+                cmd.stderr(Stdio::from(write));
+                None
+            };
+            unsafe {
+                cmd.pre_exec(|| {
+                    if libc::setsid() < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            (None, read, stderr)
+        };
+
+        let cgroup = match &cfg.cgroup {
+            Some(parent) => {
+                let cgroup = Cgroup::create(parent)?;
+                let set = || -> Result<()> {
+                    if let Some(memory) = limits.memory {
+                        cgroup.set("memory.max", &memory.to_string())?;
+                    }
+                    if let Some(processes) = limits.processes {
+                        cgroup.set("pids.max", &processes.to_string())?;
+                    }
+                    Ok(())
+                };
+                if let Err(e) = set() {
+                    cgroup.destroy();
+                    return Err(e);
+                }
+                let cgroup = Arc::new(cgroup);
+                let cgroup_ = cgroup.clone();
+                unsafe {
+                    cmd.pre_exec(move || cgroup_.enter());
+                }
+                Some(cgroup)
+            },
+            None => None,
+        };
+
+        let rlimits = rlimits(limits, cgroup.is_some());
+        if !rlimits.is_empty() {
+            unsafe {
+                cmd.pre_exec(move || {
+                    for &(resource, soft, hard) in &rlimits {
+                        setrlimit(resource, soft, hard)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        if let Some(creds) = creds {
+            let creds = creds.clone();
+            unsafe {
+                cmd.pre_exec(move || creds.apply());
+            }
+        }
+
+        let spawned = cmd.spawn();
+        // The pty slave and pipe ends only the child needs go away with cmd,
+        // and so does its hold on the cgroup
+        drop(cmd);
+        let cgroup = cgroup.map(|c| Arc::try_unwrap(c).expect("Only the command held on to the cgroup"));
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                if let Some(cgroup) = cgroup {
+                    cgroup.destroy();
+                }
+                return Err(e).with_context(|| format!("Failed to start {}", shell));
+            },
+        };
+        let stdin = match &pty {
+            Some(master) => master.try_clone()?,
+            None => File::from(OwnedFd::from(child.stdin.take().unwrap())),
+        };
+        let pid = Pid::from_raw(child.id() as i32);
+        // A child that doesn't read its input mustn't hold up the connection,
+        // the queue is kept short by holding back the host instead
+        let (stdin_tx, stdin_rx) = crossbeam_channel::unbounded();
+        let waker_ = waker.clone();
+        thread::spawn(move || cp_chan_to_stream(stdin_rx, stdin, waker_));
+
+        let recorder = match &cfg.recording {
+            Some(rec_cfg) if opts.command.is_none() && opts.pty => {
+                match Recorder::create(rec_cfg, waker.id(), WINDOW, &command) {
+                    Ok(rec) => Some(Arc::new(Mutex::new(rec))),
+                    Err(e) => {
+                        warn!("Not recording shell session: {:#}", e);
+                        None
+                    },
+                }
+            },
+            _ => None,
+        };
+
+        let (v2, on_pty) = (opts.v2, opts.pty);
+        let (drained_tx, drained_rx) = crossbeam_channel::bounded(2);
+        let mut readers = 1;
+        if let Some(stderr) = stderr {
+            let (tx, drained_tx) = (tx.clone(), drained_tx.clone());
+            thread::spawn(move || {
+                cp_stream_to_chan(stderr, tx, Some(id::STDERR), None);
+                let _ = drained_tx.send(());
+            });
+            readers += 1;
+        }
+        let tx_ = tx.clone();
+        let recorder_ = recorder.clone();
+        thread::spawn(move || {
+            cp_stream_to_chan(stdout, tx_, v2.then_some(id::STDOUT), recorder_);
+            let _ = drained_tx.send(());
+        });
+
+        let status = Arc::new(Mutex::new(None));
+        let done = Arc::new(AtomicBool::new(false));
+        let (status_, done_) = (status.clone(), done.clone());
+        let (exited_tx, exited) = crossbeam_channel::bounded::<()>(0);
+        let timed_out = Arc::new(AtomicBool::new(false));
+        if let Some(timeout) = limits.timeout.map(Duration::from_secs) {
+            let (exited, timed_out, command) = (exited.clone(), timed_out.clone(), command.clone());
+            thread::spawn(move || {
+                if exited.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                    warn!("{} timed out after {:?}", command, timeout);
+                    timed_out.store(true, Ordering::SeqCst);
+                    signal_session(pid, Signal::SIGKILL);
+                }
+            });
+        }
+        let waiter = thread::spawn(move || {
+            let ret = child.wait()
+                .unwrap_or_else(|_| ExitStatus::from_raw(1 << 8));
+            let code = if timed_out.load(Ordering::SeqCst) { TIMED_OUT } else { exit_code(ret) };
+            *status_.lock().unwrap() = Some(code);
+            drop(exited_tx);
+            // All output goes out ahead of the exit status. A background job
+            // can keep the pty open though, so there that's only waited for
+            // while the output is still moving.
+            let mut left = readers;
+            while left > 0 {
+                match drained_rx.recv_timeout(PTY_LINGER) {
+                    Ok(()) => left -= 1,
+                    Err(RecvTimeoutError::Timeout) if !on_pty || tx.is_full() => (),
+                    Err(_) => break,
+                }
+            }
+            if v2 {
+                let _ = tx.send(packet(id::EXIT, &[code as u8]));
+            }
+            drop(tx);
+            done_.store(true, Ordering::SeqCst);
+            waker.wake();
+        });
 
         Ok(Box::new(Self {
             rx,
-            child_stdin,
-            child,
+            command,
+            v2,
+            stdin: Some(stdin_tx),
+            pty,
+            input: Vec::new(),
+            pid,
+            status,
+            done,
+            exited,
+            waiter: Some(waiter),
+            cgroup,
+            recorder,
         }))
     }
+    fn write_stdin(&mut self, data: &[u8]) -> Result<()> {
+        if let Some(rec) = &self.recorder {
+            rec.lock().unwrap().input(data);
+        }
+        if let Some(stdin) = &self.stdin {
+            // Gone once the child stopped reading, like a write to a closed
+            // pipe the data has nowhere to go
+            let _ = stdin.send(data.to_vec());
+        }
+        Ok(())
+    }
+    fn handle_packet(&mut self, id: u8, data: &[u8]) -> Result<()> {
+        match id {
+            // Raw stdin goes through untouched, ^C turns into SIGINT by the
+            // pty's line discipline. Without a pty the host hangs up instead.
+            id::STDIN => self.write_stdin(data)?,
+            // A pty has stdout on the same fd
+            id::CLOSE_STDIN if self.pty.is_none() => self.stdin = None,
+            id::CLOSE_STDIN => (),
+            id::WINDOW_SIZE => self.resize(data)?,
+            _ => debug!("Ignoring shell packet {} from the host", id),
+        }
+        Ok(())
+    }
+    /// Applies a window size packet, `ROWSxCOLS,XPIXELSxYPIXELS`.
+    fn resize(&mut self, data: &[u8]) -> Result<()> {
+        let Some(pty) = &self.pty else { return Ok(()); };
+
+        let text = String::from_utf8_lossy(data);
+        let nums: Vec<u16> = text.trim_end_matches('\0')
+            .split([',', 'x'])
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("Invalid window size {:?}", text))?;
+        let &[rows, cols, xpixels, ypixels] = nums.as_slice() else {
+            bail!("Invalid window size {:?}", text);
+        };
+
+        let size = Winsize { ws_row: rows, ws_col: cols, ws_xpixel: xpixels, ws_ypixel: ypixels };
+        if unsafe { libc::ioctl(pty.as_raw_fd(), libc::TIOCSWINSZ, &size) } < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to resize the pty");
+        }
+        if let Some(rec) = &self.recorder {
+            rec.lock().unwrap().resize(cols, rows);
+        }
+        Ok(())
+    }
+}
+
+/// Sends `sig` to every process in session `sid`, which with job control
+/// spans several process groups.
+fn signal_session(sid: Pid, sig: Signal) {
+    let Ok(procs) = fs::read_dir("/proc") else { return; };
+    for pid in procs.filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok()) {
+        let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) else { continue; };
+        // The command name in parentheses can have anything in it
+        let Some((_, fields)) = stat.rsplit_once(')') else { continue; };
+        if fields.split_whitespace().nth(3) == Some(&sid.to_string()) {
+            let _ = kill(Pid::from_raw(pid), sig);
+        }
+    }
+}
+
+/// The rlimits to set for `limits`, those a cgroup doesn't take care of.
+fn rlimits(limits: &Limits, cgroup: bool) -> Vec<(Resource, u64, u64)> {
+    let mut ret = Vec::new();
+    if let Some(cpu) = limits.cpu {
+        // SIGXCPU first, SIGKILL a second later
+        ret.push((Resource::RLIMIT_CPU, cpu, cpu + 1));
+    }
+    if !cgroup {
+        if let Some(memory) = limits.memory {
+            ret.push((Resource::RLIMIT_AS, memory, memory));
+        }
+        if let Some(processes) = limits.processes {
+            ret.push((Resource::RLIMIT_NPROC, processes, processes));
+        }
+    }
+    ret
 }
 
-fn cp_stream_to_chan(mut from: impl Read, to: Sender<Vec<u8>>) {
+/// Killed by a signal shows up as 128 plus its number, like in sh.
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().or(status.signal().map(|sig| 128 + sig)).unwrap_or(1)
+}
+
+pub(crate) fn packet(id: u8, data: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(HEADER_LEN + data.len());
+    ret.push(id);
+    ret.extend((data.len() as u32).to_le_bytes());
+    ret.extend(data);
+    ret
+}
+
+/// The configured shell, or `user`'s unless that one doesn't take logins.
+fn login_shell(cfg: &ShellConfig, user: &User) -> String {
+    if let Some(shell) = &cfg.default {
+        return shell.clone();
+    }
+    let shell = user.shell.to_string_lossy();
+    if shell.is_empty() || shell.ends_with("/nologin") || shell.ends_with("/false") {
+        debug!("{} has no usable login shell, using {}", user.name, DEFAULT_SHELL);
+        DEFAULT_SHELL.to_string()
+    } else {
+        shell.into_owned()
+    }
+}
+
+/// Forwards output, as v2 packets with `id` if given.
+fn cp_stream_to_chan(mut from: impl Read, to: Sender<Vec<u8>>, id: Option<u8>,
+                     recorder: Option<Arc<Mutex<Recorder>>>) {
+    let max = MAXDATA as usize - if id.is_some() { HEADER_LEN } else { 0 };
     loop {
-        let mut buf = vec![0; MAXDATA as usize];
+        let mut buf = vec![0; max];
         let Ok(n) = from.read(&mut buf) else { return; };
         if n == 0 { return; }
         buf.resize(n, 0);
-    
+
+        if let Some(rec) = &recorder {
+            rec.lock().unwrap().output(&buf);
+        }
+        if let Some(id) = id {
+            buf = packet(id, &buf);
+        }
         if to.send(buf).is_err() {
             break;
         }
     }
 }
+
+/// Feeds the child's stdin, waking the stream each time there's room for
+/// more.
+fn cp_chan_to_stream(from: Receiver<Vec<u8>>, mut to: File, waker: Waker) {
+    for data in from {
+        if let Err(e) = to.write_all(&data) {
+            debug!("Stream {} stopped taking input: {}", waker.id(), e);
+            return;
+        }
+        waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_v2_options() {
+        assert_eq!(Options::parse("v2,TERM=xterm-256color,pty:"), Options {
+            command: None,
+            pty: true,
+            v2: true,
+            term: Some("xterm-256color".to_string()),
+        });
+        let opts = Options::parse("v2,raw:ls -l /tmp:x");
+        assert_eq!((opts.command.as_deref(), opts.pty), (Some("ls -l /tmp:x"), false));
+        assert!(Options::parse("v2:ls").v2);
+        assert!(!Options::parse("v2:ls").pty);
+        assert!(Options::parse("v2,pty:ls").pty);
+    }
+
+    #[test]
+    fn stdin_doesnt_block_the_connection() {
+        let (wake_tx, _wake_rx) = crossbeam_channel::unbounded();
+        let opts = Options { command: Some("sleep 5".to_string()), ..Options::default() };
+        let mut svc = ShellService::start(opts, &ShellConfig::default(), &Limits::default(), None,
+                                          Waker::new(1, wake_tx)).unwrap();
+        // Far more than the pipe holds, with nothing reading it
+        for _ in 0..16 {
+            assert!(svc.handle_write(vec![b'x'; MAXDATA as usize]).is_ok());
+        }
+        assert!(svc.input_full());
+        svc.close().unwrap();
+    }
+
+    #[test]
+    fn raw_stdin_is_just_data() {
+        let (wake_tx, _wake_rx) = crossbeam_channel::unbounded();
+        let opts = Options { command: Some("cat".to_string()), v2: true, ..Options::default() };
+        let mut svc = ShellService::start(opts, &ShellConfig::default(), &Limits::default(), None,
+                                          Waker::new(1, wake_tx)).unwrap();
+        let mut input = packet(id::STDIN, b"\x03");
+        input.extend(packet(id::CLOSE_STDIN, &[]));
+        svc.handle_write(input).unwrap();
+
+        let mut data = Vec::new();
+        while let Ok(chunk) = svc.recv().recv_timeout(Duration::from_secs(5)) {
+            data.extend(chunk);
+        }
+        let mut expected = packet(id::STDOUT, b"\x03");
+        expected.extend(packet(id::EXIT, &[0]));
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn output_goes_out_before_the_exit_status() {
+        let (wake_tx, wake_rx) = crossbeam_channel::unbounded();
+        let script = "i=0; while [ $i -lt 20 ]; do i=$((i+1)); echo line$i; sleep 0.01; done";
+        let opts = Options { command: Some(script.to_string()), v2: true, ..Options::default() };
+        let mut svc = ShellService::start(opts, &ShellConfig::default(), &Limits::default(), None,
+                                          Waker::new(1, wake_tx)).unwrap();
+        // More chunks than the queue holds, left there until the shell is
+        // gone and then taken slowly like by a slow host
+        thread::sleep(Duration::from_millis(800));
+        let mut data = Vec::new();
+        while let Ok(chunk) = svc.recv().recv_timeout(Duration::from_secs(5)) {
+            data.extend(chunk);
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let (mut stdout, mut exit) = (Vec::new(), None);
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            assert_eq!(exit, None, "Output after the exit status");
+            let len = u32::from_le_bytes(rest[1..HEADER_LEN].try_into().unwrap()) as usize;
+            let payload = &rest[HEADER_LEN..HEADER_LEN + len];
+            match rest[0] {
+                id::STDOUT => stdout.extend(payload),
+                id::EXIT => exit = Some(payload.to_vec()),
+                other => panic!("Unexpected packet {}", other),
+            }
+            rest = &rest[HEADER_LEN + len..];
+        }
+        let expected: String = (1..=20).map(|i| format!("line{}\n", i)).collect();
+        assert_eq!(String::from_utf8(stdout).unwrap(), expected);
+        assert_eq!(exit, Some(vec![0]));
+        wake_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(svc.is_done());
+        assert_eq!(svc.exit_status(), Some(0));
+    }
+}
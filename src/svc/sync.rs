@@ -1,15 +1,31 @@
-use crate::svc::Service;
-use std::path::PathBuf;
+use crate::privileges::Credentials;
+use crate::svc::{Service, OUTPUT_QUEUE_LEN};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::{Component, Path, PathBuf};
+use nix::fcntl::{open, openat, OFlag};
 use nix::sys::stat::{stat, mode_t, Mode};
 use crossbeam_channel::{Sender, Receiver};
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+
+/// Symlinks one path may go through before it counts as a loop, Linux's
+/// MAXSYMLINKS.
+const MAX_LINKS: usize = 40;
+
+/// Longest record after the header, adb's SYNC_DATA_MAX. Anything longer
+/// is garbage, not worth waiting for.
+const MAX_RECORD: usize = 64 * 1024;
 
 #[derive(Debug, Clone)]
 #[repr(u32)]
+#[allow(dead_code)]
 enum Request {
     List,
     Recv,
-    Send{mode: Mode, data: Vec<u8>, append: bool},
+    Send{mode: Mode},
     Stat,
     Quit,
 }
@@ -17,12 +33,12 @@ enum Request {
 #[derive(Debug, Clone)]
 enum Response {
     Stat{mode: u32, size: u32, mtime: u32},
-    Fail,
+    Fail(String),
     Okay,
 }
 
 impl Response {
-    fn to_bytes(self) -> Vec<u8> {
+    fn into_bytes(self) -> Vec<u8> {
         let mut ret = Vec::new();
         match self {
             Response::Stat{mode, size, mtime} => {
@@ -31,7 +47,11 @@ impl Response {
                 ret.extend(u32::to_le_bytes(size));
                 ret.extend(u32::to_le_bytes(mtime));
             },
-            Response::Fail => ret.extend(b"FAIL"),
+            Response::Fail(msg) => {
+                ret.extend(b"FAIL");
+                ret.extend(u32::to_le_bytes(msg.len() as u32));
+                ret.extend(msg.as_bytes());
+            },
             Response::Okay => ret.extend(b"OKAY"),
         };
         ret
@@ -40,80 +60,99 @@ impl Response {
 
 enum State {
     Normal,
-    SendMoreData,
+    /// DATA chunks of a SEND until DONE, the first error is reported then.
+    Receiving{path: PathBuf, mode: Mode, created: bool, error: Option<String>},
 }
 
 pub 
 struct SyncService {
     state: State,
+    roots: Vec<PathBuf>,
+    /// Further limit from the policy.
+    limit: Option<Vec<PathBuf>>,
+    /// Who file operations happen as, radbd itself if None.
+    creds: Option<Credentials>,
+    /// Whether `adb remount` is there to suggest for read-only filesystems.
+    remount: bool,
     rx: Receiver<Vec<u8>>,
     tx: Sender<Vec<u8>>,
     done: bool,
+    /// The start of a record the rest of which hasn't come yet.
+    buf: Vec<u8>,
 }
 
 impl Service for SyncService {
+    /// Records may come split over several writes or several to a write,
+    /// whatever isn't complete yet waits for the next one.
     fn handle_write(&mut self, packet: Vec<u8>) -> Result<()> {
-        let (cmd, path) = match &packet[0..4] {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.extend(packet);
+        let mut start = 0;
+        while let Some(len) = record_len(&buf[start..])? {
+            self.handle_record(&buf[start..start + len])?;
+            start += len;
+        }
+        buf.drain(..start);
+        self.buf = buf;
+        Ok(())
+    }
+    fn recv(&mut self) -> &mut Receiver<Vec<u8>> {
+        &mut self.rx
+    }
+    fn is_done(&mut self) -> bool {
+        self.done
+    }
+    /// Replies get queued right as requests come in, so there's no taking
+    /// more of those while the host isn't reading the replies.
+    fn input_full(&self) -> bool {
+        self.tx.len() >= OUTPUT_QUEUE_LEN
+    }
+}
+
+impl SyncService {
+    pub fn start(roots: Vec<PathBuf>, limit: Option<Vec<PathBuf>>, creds: Option<Credentials>,
+                 remount: bool) -> Result<Box<dyn Service>> {
+        // Unbounded as one write may hold any number of requests, there's
+        // no waiting for room without stalling the connection
+        let (tx, rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+
+        Ok(Box::new(Self {
+            tx,
+            rx,
+            done: false,
+            buf: Vec::new(),
+            state: State::Normal,
+            roots,
+            limit,
+            creds,
+            remount,
+        }))
+    }
+    fn handle_record(&mut self, record: &[u8]) -> Result<()> {
+        if let State::Receiving{..} = self.state {
+            return self.receive(record);
+        }
+
+        let body = &record[8..];
+        let (cmd, path) = match &record[0..4] {
             //b"LIST" => Request::List,
             //b"RECV" => Request::Recv,
             b"SEND" => {
-                let len = u32::from_le_bytes([packet[4], packet[5], packet[6], packet[7]]) as usize;
-                let path_mode_str = String::from_utf8_lossy(&packet[8..][..len]).to_string();
-                let path_mode: Vec<&str> = path_mode_str.split(',').collect();
-                let path = path_mode.get(0).unwrap();
-                let mode_raw = path_mode.get(1).unwrap().parse::<u32>()? as mode_t;
+                let path_mode_str = String::from_utf8_lossy(body).to_string();
+                let (path, mode_raw) = path_mode_str.rsplit_once(',')
+                    .with_context(|| format!("SEND without a mode: {:?}", path_mode_str))?;
+                let mode_raw = mode_raw.parse::<u32>()? as mode_t;
                 let mode = Mode::from_bits_truncate(mode_raw);
 
                 if mode.bits() != mode_raw {
-                    eprintln!("Unsupported bits found: {:x}", mode.bits() ^ mode_raw);
+                    warn!("Unsupported bits found: {:x}", mode.bits() ^ mode_raw);
                 }
 
-                let mut data = Vec::new();
-                let mut offset = 8 + len;
-
-                println!("{:x?}", String::from_utf8_lossy(&packet[offset..]));
-
-                if packet[offset..][..4] != *b"DATA" {
-                    bail!("Invalid stuff found idk i'm tired you write a better error message");
-                }
-                offset += 4;
-
-                let data_len = u32::from_le_bytes([packet[offset],
-                                                  packet[offset + 1],
-                                                  packet[offset + 2],
-                                                  packet[offset + 3]]) as usize;
-                offset += 4;
-
-                data.extend(&packet[offset..][..data_len]);
-                offset += data_len;
-                let append;
-
-                if packet.len() != offset {
-                    if packet[offset..][..4] != *b"DONE" {
-                        bail!("Expected {:x?}(DONE), found {:x?}", b"DONE", &packet[offset..][..4])
-                    }
-                    
-                    // Note: at offset+4 there is a u32 for creation time. we don't care.
-
-                    self.state = State::Normal;
-                    append = false;
-                } else {
-                    self.state = State::SendMoreData;
-                    append = true;
-                }
-
-                println!("{:?} {:x?}", path, mode);
-
-                (Request::Send{mode, data, append}, PathBuf::from(path))
+                debug!("SEND {:?} with mode {:o}", path, mode.bits());
+                (Request::Send{mode}, PathBuf::from(path))
             },
             b"STAT" => {
-                let len = u32::from_le_bytes([packet[4], packet[5], packet[6], packet[7]]);
-                let path = String::from_utf8_lossy(&packet[8..]).to_string();
-
-                if path.len() != len as usize {
-                    bail!("Length of path({:x}) isn't equal to length expected({:x})", path.len(), len)
-                }
-                (Request::Stat, PathBuf::from(path))
+                (Request::Stat, PathBuf::from(String::from_utf8_lossy(body).to_string()))
             },
             b"QUIT" => {
                 (Request::Quit, PathBuf::from("/dev/null"))
@@ -123,20 +162,27 @@ impl Service for SyncService {
             },
         };
 
+        let denied = (!matches!(cmd, Request::Quit) && !self.allowed(&path))
+            .then(|| format!("{} is outside of the directories sync may access", path.display()));
+
         let response = match cmd {
-            Request::Stat => {
-                let stat = stat(&path).unwrap();
-                Response::Stat{
+            Request::Send{mode} => {
+                // The verdict goes out after DONE, once the data is read
+                self.state = State::Receiving{path, mode, created: false, error: denied};
+                return Ok(());
+            },
+            // A missing file is reported as all zeroes, so is one sync may not
+            // look at, adb takes a FAIL there for a broken connection
+            Request::Stat if denied.is_some() => Response::Stat{size: 0, mode: 0, mtime: 0},
+            _ if denied.is_some() => Response::Fail(denied.unwrap()),
+            Request::Stat => match self.as_user(|| stat(&path)) {
+                Ok(stat) => Response::Stat{
                     size: stat.st_size as u32,
                     mode: stat.st_mode,
                     mtime: stat.st_mtime as u32,
-                }
+                },
+                Err(_) => Response::Stat{size: 0, mode: 0, mtime: 0},
             },
-            Request::Send{..} => {
-                eprintln!("Writing into {:?} goes here", path);
-
-                Response::Okay
-            }
             Request::Quit => {
                 self.done = true;
                 Response::Okay
@@ -144,27 +190,244 @@ impl Service for SyncService {
             _ => todo!(),
         };
 
-        self.tx.send(response.to_bytes())?;
+        self.tx.send(response.into_bytes())?;
 
         Ok(())
     }
-    fn recv(&mut self) -> &mut Receiver<Vec<u8>> {
-        &mut self.rx
+    fn allowed(&self, path: &Path) -> bool {
+        self.as_user(|| resolve(path))
+            .is_some_and(|path| within(&path, &self.roots, self.limit.as_deref()))
     }
-    fn is_done(&mut self) -> bool {
-        self.done
+    fn as_user<T>(&self, f: impl FnOnce() -> T) -> T {
+        match &self.creds {
+            Some(creds) => creds.with_fs_ids(f),
+            None => f(),
+        }
+    }
+    /// Handles a DATA or DONE record of a SEND.
+    fn receive(&mut self, record: &[u8]) -> Result<()> {
+        match &record[..4] {
+            b"DATA" => self.write_chunk(&record[8..]),
+            b"DONE" => {
+                self.write_chunk(&[]);
+                let State::Receiving{error, ..} = std::mem::replace(&mut self.state, State::Normal) else {
+                    unreachable!();
+                };
+                let response = match error {
+                    Some(msg) => Response::Fail(msg),
+                    None => Response::Okay,
+                };
+                self.tx.send(response.into_bytes())?;
+            },
+            other => bail!("Expected DATA or DONE, found {:x?}", other),
+        }
+        Ok(())
+    }
+    fn write_chunk(&mut self, data: &[u8]) {
+        let State::Receiving{path, mode, created, error} = &mut self.state else {
+            return;
+        };
+        if error.is_some() || (*created && data.is_empty()) {
+            return;
+        }
+
+        let truncate = !*created;
+        let allowed = |real: &Path| within(real, &self.roots, self.limit.as_deref());
+        let ret = self.creds.as_ref().map_or_else(
+            || write_file(path, *mode, truncate, data, allowed),
+            |creds| creds.with_fs_ids(|| write_file(path, *mode, truncate, data, allowed)));
+        match ret {
+            Ok(()) => *created = true,
+            Err(e) if self.remount && e.raw_os_error() == Some(libc::EROFS) => {
+                *error = Some(format!("Failed to write {}: {}, try `adb remount` first", path.display(), e));
+            },
+            Err(e) => *error = Some(format!("Failed to write {}: {}", path.display(), e)),
+        }
     }
 }
 
-impl SyncService {
-    pub fn start() -> Result<Box<dyn Service>> {
-        let (tx, rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+/// Length of the record `buf` starts with, None until all of it is there.
+fn record_len(buf: &[u8]) -> Result<Option<usize>> {
+    let Some(header) = buf.get(..8) else {
+        return Ok(None);
+    };
+    let len = match &header[..4] {
+        // The length is the mtime for DONE
+        b"DONE" => 0,
+        _ => u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize,
+    };
+    if len > MAX_RECORD {
+        bail!("Sync record of {} bytes is too long", len);
+    }
+    Ok((buf.len() >= 8 + len).then_some(8 + len))
+}
 
-        Ok(Box::new(Self {
-            tx,
-            rx,
-            done: false,
-            state: State::Normal,
-        }))
+/// Writes to `path` if where it really is passes `allowed`.
+///
+/// That's checked on the directory as opened and the file is opened without
+/// following a symlink, so nothing swapped in after [`SyncService::allowed`]
+/// can send the data elsewhere.
+fn write_file(path: &Path, mode: Mode, truncate: bool, data: &[u8],
+              allowed: impl Fn(&Path) -> bool) -> io::Result<()> {
+    let resolved = resolve(path).ok_or(io::Error::from_raw_os_error(libc::ELOOP))?;
+    let (Some(parent), Some(name)) = (resolved.parent(), resolved.file_name()) else {
+        return Err(io::Error::from_raw_os_error(libc::EISDIR));
+    };
+    let dir = open(parent, OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, Mode::empty())?;
+    let dir = unsafe { File::from_raw_fd(dir) };
+    let real = fs::read_link(format!("/proc/self/fd/{}", dir.as_raw_fd()))?;
+    if !allowed(&real.join(name)) {
+        return Err(io::Error::from_raw_os_error(libc::EACCES));
+    }
+
+    let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC
+        | if truncate { OFlag::O_TRUNC } else { OFlag::O_APPEND };
+    let fd = openat(dir.as_raw_fd(), name, flags, mode)?;
+    unsafe { File::from_raw_fd(fd) }.write_all(data)
+}
+
+/// Whether the real path `path` is under one of `roots` and, if given, one
+/// of `limit`.
+fn within(path: &Path, roots: &[PathBuf], limit: Option<&[PathBuf]>) -> bool {
+    let under = |dirs: &[PathBuf]| dirs.iter()
+        .any(|dir| resolve(dir).is_some_and(|dir| path.starts_with(dir)));
+    under(roots) && limit.is_none_or(under)
+}
+
+/// Where `path` really is, for comparing it against the sync roots.
+///
+/// Goes one component at a time like the kernel does, so a `..` applies to
+/// where a symlink led and a dangling symlink counts as its target. None
+/// for a symlink loop.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let mut ret = PathBuf::from("/");
+    let mut todo: Vec<OsString> = path.components().rev()
+        .map(|comp| comp.as_os_str().to_owned())
+        .collect();
+    let mut links = 0;
+    while let Some(comp) = todo.pop() {
+        match Path::new(&comp).components().next() {
+            Some(Component::RootDir) => ret = PathBuf::from("/"),
+            Some(Component::ParentDir) => { ret.pop(); },
+            Some(Component::Normal(name)) => {
+                let next = ret.join(name);
+                match fs::read_link(&next) {
+                    Ok(target) => {
+                        links += 1;
+                        if links > MAX_LINKS {
+                            return None;
+                        }
+                        todo.extend(target.components().rev().map(|comp| comp.as_os_str().to_owned()));
+                    },
+                    Err(_) => ret = next,
+                }
+            },
+            _ => (),
+        }
+    }
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn push(svc: &mut Box<dyn Service>, path: &Path) -> Vec<u8> {
+        let name = format!("{},420", path.display());
+        let mut packet = b"SEND".to_vec();
+        packet.extend((name.len() as u32).to_le_bytes());
+        packet.extend(name.as_bytes());
+        packet.extend(b"DATA\x02\0\0\0hi");
+        packet.extend(b"DONE\0\0\0\0");
+        svc.handle_write(packet).unwrap();
+        svc.recv().try_recv().unwrap()
+    }
+
+    #[test]
+    fn records_may_span_writes() {
+        let root = std::env::temp_dir().join(format!("radbd-sync-split-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let root = root.canonicalize().unwrap();
+        let file = root.join("file");
+
+        let name = format!("{},420", file.display());
+        let mut stream = b"SEND".to_vec();
+        stream.extend((name.len() as u32).to_le_bytes());
+        stream.extend(name.as_bytes());
+        for chunk in [&b"hello"[..], b" ", b"world"] {
+            stream.extend(b"DATA");
+            stream.extend((chunk.len() as u32).to_le_bytes());
+            stream.extend(chunk);
+        }
+        stream.extend(b"DONE\x2a\0\0\0");
+
+        for split in [&[1, 7, 9][..], &[8, 20], &[stream.len() - 1], &[3, 50, 51, 60]] {
+            let mut svc = SyncService::start(vec![root.clone()], None, None, false).unwrap();
+            let mut start = 0;
+            for &end in split.iter().chain([&stream.len()]) {
+                svc.handle_write(stream[start..end].to_vec()).unwrap();
+                start = end;
+            }
+            assert_eq!(svc.recv().try_recv().unwrap(), b"OKAY", "split at {:?}", split);
+            assert_eq!(fs::read(&file).unwrap(), b"hello world");
+            fs::remove_file(&file).unwrap();
+        }
+
+        // One byte at a time, then a whole push and a QUIT in one write
+        let mut svc = SyncService::start(vec![root.clone()], None, None, false).unwrap();
+        for byte in &stream {
+            svc.handle_write(vec![*byte]).unwrap();
+        }
+        let mut twice = stream.clone();
+        twice.extend(b"QUIT\0\0\0\0");
+        svc.handle_write(twice).unwrap();
+        assert_eq!(svc.recv().try_iter().collect::<Vec<_>>(), [b"OKAY"; 3]);
+        assert!(svc.is_done());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn symlinks_dont_lead_out_of_the_roots() {
+        let base = std::env::temp_dir().join(format!("radbd-sync-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).unwrap();
+        let base = base.canonicalize().unwrap();
+        let (root, out) = (base.join("root"), base.join("out"));
+        fs::create_dir(&root).unwrap();
+        fs::create_dir(&out).unwrap();
+        symlink(&out, root.join("link")).unwrap();
+        symlink(out.join("foo"), root.join("evil")).unwrap();
+        symlink("sub", root.join("inside")).unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+
+        let mut svc = SyncService::start(vec![root.clone()], None, None, false).unwrap();
+        assert_eq!(push(&mut svc, &root.join("inside/ok")), b"OKAY");
+        assert_eq!(fs::read(root.join("sub/ok")).unwrap(), b"hi");
+
+        assert_eq!(resolve(&root.join("link/../x")), Some(base.join("x")));
+        assert_eq!(&push(&mut svc, &root.join("link/../x"))[..4], b"FAIL");
+        assert!(!base.join("x").exists());
+
+        assert_eq!(resolve(&root.join("evil")), Some(out.join("foo")));
+        assert_eq!(&push(&mut svc, &root.join("evil"))[..4], b"FAIL");
+        assert!(!out.join("foo").exists());
+
+        let link = root.join("link").display().to_string();
+        let mut packet = b"STAT".to_vec();
+        packet.extend((link.len() as u32).to_le_bytes());
+        packet.extend(link.as_bytes());
+        svc.handle_write(packet).unwrap();
+        assert_eq!(svc.recv().try_recv().unwrap(), b"STAT\0\0\0\0\0\0\0\0\0\0\0\0");
+
+        // Writing checks again where the data would end up
+        let allowed = |real: &Path| real.starts_with(&root);
+        assert!(write_file(&root.join("evil"), Mode::from_bits_truncate(0o644), true, b"hi", allowed).is_err());
+        assert!(!out.join("foo").exists());
+
+        symlink("loop", root.join("loop")).unwrap();
+        assert_eq!(resolve(&root.join("loop")), None);
+        fs::remove_dir_all(&base).unwrap();
     }
 }
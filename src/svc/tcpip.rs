@@ -0,0 +1,196 @@
+//! `tcpip:PORT` and `usb:`, for `adb tcpip` and `adb usb`.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use crossbeam_channel::Receiver;
+use log::{error, info};
+use crate::config::Config;
+use crate::svc::{Service, Waker};
+
+/// Gives the reply a chance to reach the host before the connection drops.
+const DELAY: Duration = Duration::from_millis(500);
+
+/// Something that has to happen before radbd replaces itself, like handing
+/// the gadget it created on to the next one.
+type Cleanup = Box<dyn FnOnce(&mut Command) + Send>;
+static CLEANUP: Mutex<Vec<Cleanup>> = Mutex::new(Vec::new());
+
+/// Restarts radbd, [`restart`] outside of tests.
+pub type Restarter = Arc<dyn Fn() -> Result<()> + Send + Sync>;
+
+/// The transport choice kept in `tcp.state`, like adbd's
+/// `service.adb.tcp.port`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Listen on this port, next to USB if that's enabled.
+    Tcp(u16),
+    /// USB only.
+    Usb,
+}
+
+impl Mode {
+    /// The saved choice, None until there is one.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let data = data.trim();
+        let ret = match data.strip_prefix("tcp:") {
+            Some(port) => port.parse().ok().filter(|&port| port != 0).map(Mode::Tcp),
+            None => (data == "usb").then_some(Mode::Usb),
+        };
+        ret.map(Some)
+            .with_context(|| format!("{}: unknown transport {:?}", path.display(), data))
+    }
+    pub fn save(self, path: &Path) -> Result<()> {
+        // A crash halfway through leaves the old choice rather than garbage
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, format!("{}\n", self))
+            .and_then(|()| fs::rename(&tmp, path))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+    /// Overrides what the config says about TCP.
+    pub fn apply(self, cfg: &mut Config) {
+        match self {
+            Mode::Tcp(port) => {
+                cfg.tcp.enabled = true;
+                cfg.tcp.listen = match cfg.tcp.listen.rsplit_once(':') {
+                    Some((host, _)) => format!("{}:{}", host, port),
+                    None => port.to_string(),
+                };
+            },
+            // Turning TCP off without USB would leave no transport at all
+            Mode::Usb if cfg.usb.enabled => cfg.tcp.enabled = false,
+            Mode::Usb => (),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mode::Tcp(port) => write!(f, "tcp:{}", port),
+            Mode::Usb => write!(f, "usb"),
+        }
+    }
+}
+
+pub struct TcpipService {
+    rx: Receiver<Vec<u8>>,
+    done: Arc<AtomicBool>,
+}
+
+impl Service for TcpipService {
+    fn handle_write(&mut self, _data: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+    fn recv(&mut self) -> &mut Receiver<Vec<u8>> {
+        &mut self.rx
+    }
+    /// Done once saving or restarting failed, a restart that worked takes
+    /// the stream with it.
+    fn is_done(&mut self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+}
+
+impl TcpipService {
+    pub fn start(mode: Mode, state: &Path, restart: Restarter, waker: Waker) -> Result<Box<dyn Service>> {
+        let (tx, rx) = crossbeam_channel::bounded(2);
+        let done = Arc::new(AtomicBool::new(false));
+        if let Err(e) = mode.save(state) {
+            error!("{:#}", e);
+            tx.send(format!("{:#}\n", e).into_bytes())?;
+            done.store(true, Ordering::SeqCst);
+            return Ok(Box::new(Self { rx, done }));
+        }
+
+        info!("Restarting with transport {}", mode);
+        tx.send(match mode {
+            Mode::Tcp(port) => format!("restarting in TCP mode port: {}\n", port),
+            Mode::Usb => "restarting in USB mode\n".to_string(),
+        }.into_bytes())?;
+        let done_ = done.clone();
+        thread::spawn(move || {
+            thread::sleep(DELAY);
+            if let Err(e) = restart() {
+                error!("Failed to restart: {:#}", e);
+                let _ = tx.send(format!("restart failed: {:#}\n", e).into_bytes());
+            }
+            done_.store(true, Ordering::SeqCst);
+            waker.wake();
+        });
+        Ok(Box::new(Self { rx, done }))
+    }
+}
+
+/// Runs `f` before radbd restarts itself, with the command that's about to
+/// run.
+pub fn at_restart(f: impl FnOnce(&mut Command) + Send + 'static) {
+    CLEANUP.lock().unwrap().push(Box::new(f));
+}
+
+/// Executes radbd again with the same arguments, it picks the transport up
+/// from `tcp.state` then.
+pub fn restart() -> Result<()> {
+    let mut args = env::args_os();
+    let arg0 = args.next().context("No argv[0] to restart with")?;
+    let exe = env::current_exe().context("Failed to find radbd's executable")?;
+    let mut cmd = Command::new(exe);
+    cmd.arg0(arg0).args(args);
+    for cleanup in CLEANUP.lock().unwrap().drain(..) {
+        cleanup(&mut cmd);
+    }
+    let err = cmd.exec();
+    Err(err).context("Failed to execute radbd again")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_the_mode_and_restarts() {
+        let state = env::temp_dir().join(format!("radbd-tcpip-{}", std::process::id()));
+        let _ = fs::remove_file(&state);
+        assert_eq!(Mode::load(&state).unwrap(), None);
+
+        let restarts = Arc::new(Mutex::new(0));
+        let restarts_ = restarts.clone();
+        let restart: Restarter = Arc::new(move || {
+            *restarts_.lock().unwrap() += 1;
+            Ok(())
+        });
+        let (wake_tx, wake_rx) = crossbeam_channel::unbounded();
+        let mut svc = TcpipService::start(Mode::Tcp(5556), &state, restart, Waker::new(1, wake_tx)).unwrap();
+        wake_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let out: Vec<u8> = svc.recv().try_iter().flatten().collect();
+        assert_eq!(String::from_utf8(out).unwrap(), "restarting in TCP mode port: 5556\n");
+        assert_eq!(*restarts.lock().unwrap(), 1);
+
+        let mode = Mode::load(&state).unwrap().unwrap();
+        assert_eq!(mode, Mode::Tcp(5556));
+        let mut cfg = Config::default();
+        cfg.tcp.listen = "127.0.0.1:5555".to_string();
+        mode.apply(&mut cfg);
+        assert!(cfg.tcp.enabled && cfg.usb.enabled);
+        assert_eq!(cfg.tcp.addr(), "127.0.0.1:5556");
+
+        fs::write(&state, "usb\n").unwrap();
+        Mode::load(&state).unwrap().unwrap().apply(&mut cfg);
+        assert!(!cfg.tcp.enabled);
+        fs::remove_file(&state).unwrap();
+    }
+}
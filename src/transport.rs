@@ -0,0 +1,65 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use anyhow::{Context, Result};
+
+pub type Reader = Box<dyn Read + Send>;
+pub type Writer = Box<dyn Write + Send>;
+
+/// A link to an adb host that messages get exchanged over.
+pub trait Transport {
+    /// Short name of the transport kind, like `usb` or `tcp`.
+    fn kind(&self) -> &'static str;
+    /// Hands out both directions of the link, can only be called once.
+    fn split(&mut self) -> Result<(Reader, Writer)>;
+    /// Whether the device has to keep sending CNXN until the host answers
+    /// instead of waiting for the host to start.
+    fn announces(&self) -> bool { false }
+}
+
+pub struct TcpTransport {
+    stream: Option<TcpStream>,
+    peer: SocketAddr,
+}
+
+impl TcpTransport {
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl Transport for TcpTransport {
+    fn kind(&self) -> &'static str {
+        "tcp"
+    }
+    fn split(&mut self) -> Result<(Reader, Writer)> {
+        let stream = self.stream.take()
+            .context("Transport was already split")?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        Ok((Box::new(reader), Box::new(stream)))
+    }
+}
+
+/// Listens for hosts connecting over TCP, like `adb connect`.
+pub struct TcpServer {
+    listener: TcpListener,
+}
+
+impl TcpServer {
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .context("Failed to bind the TCP listener")?;
+        Ok(Self { listener })
+    }
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+    pub fn accept(&self) -> Result<TcpTransport> {
+        let (stream, peer) = self.listener.accept()
+            .context("Failed to accept a connection")?;
+        Ok(TcpTransport {
+            stream: Some(stream),
+            peer,
+        })
+    }
+}
@@ -1,315 +1,628 @@
-use std::mem;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
 use libusb1_sys::constants as libusb;
+use anyhow::{bail, Context, Result};
+use crate::aio;
+use crate::proto::MAXDATA;
+use crate::transport::{Reader, Transport, Writer};
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-struct InterfaceDesc {
-    length: u8,
-    desc_type: u8,
-    interface_num: u8,
-    alt_setting: u8,
-    num_endpoints: u8,
-    class: u8,
-    subclass: u8,
-    proto: u8,
-    interface: u8,
-}
+const FUNCTIONFS_STRINGS_MAGIC: u32 = 2;
+const FUNCTIONFS_DESCRIPTORS_MAGIC_V2: u32 = 3;
+
+const FFS_HAS_FS_DESC: u32 = 1;
+const FFS_HAS_HS_DESC: u32 = 2;
+const FFS_HAS_SS_DESC: u32 = 4;
+const FFS_HAS_MS_OS_DESC: u32 = 8;
+
+const USB_DT_INTERFACE_SIZE: u8 = 9;
+const USB_DT_ENDPOINT_SIZE: u8 = 7;
+const USB_DT_SS_EP_COMP_SIZE: u8 = 6;
+const OS_DESC_HEADER_SIZE: usize = 11;
+const OS_DESC_EXT_COMPAT_SIZE: usize = 24;
 
-const USB_DT_INTERFACE_SIZE: usize = 9;
 const ADB_CLASS: u8 = 0xff;
 const ADB_SUBCLASS: u8 = 0x42;
 const ADB_PROTOCOL: u8 = 0x1;
 
-const ADB_INTERFACE: InterfaceDesc = InterfaceDesc {
-    length: USB_DT_INTERFACE_SIZE as u8,
-    desc_type: libusb::LIBUSB_DT_INTERFACE,
-    interface_num: 0,
-    alt_setting: 0,
-    num_endpoints: 2,
-    class: ADB_CLASS,
-    subclass: ADB_SUBCLASS,
-    proto: ADB_PROTOCOL,
-    interface: 1,
-};
+const MAX_PACKET_SIZE_FS: u16 = 64;
+const MAX_PACKET_SIZE_HS: u16 = 512;
+const MAX_PACKET_SIZE_SS: u16 = 1024;
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-struct EndpointDescNoAudio {
-    length: u8,
-    desc_type: u8,
-    addr: u8,
-    attr: u8,
-    max_packet_size: u16,
-    interval: u8,
+pub const USB_DIR_OUT: u8 = 0;
+pub const USB_DIR_IN: u8 = 0x80;
+
+pub const USB_ENDPOINT_XFER_BULK: u8 = 2;
+
+const DEV_IFACE_GUID: &[u8] = b"DeviceInterfaceGUID\0";
+const ADB_GUID: &[u8] = b"{F72FE0D4-CBCB-407D-8814-9ED673D0DD6B}\0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    Full,
+    High,
+    Super,
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-struct OsHeader {
-    iface: u8,
-    len: u32,
-    bcd_ver: u16,
-    idx: u16,
-    count: u8,
-    reserved: u8,
+impl Speed {
+    fn flag(self) -> u32 {
+        match self {
+            Speed::Full => FFS_HAS_FS_DESC,
+            Speed::High => FFS_HAS_HS_DESC,
+            Speed::Super => FFS_HAS_SS_DESC,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-struct ExtCompatDesc {
-    first_iface_num: u8,
-    res1: u8,
-    compat_id: [u8; 8],
-    sub_compat_id: [u8; 8],
-    res_2: [u8; 6],
+pub struct SsCompanion {
+    pub max_burst: u8,
+    pub attr: u8,
+    pub bytes_per_interval: u16,
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-struct FuncDesc {
-    interface: InterfaceDesc,
-    source: EndpointDescNoAudio,
-    sink: EndpointDescNoAudio,
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub addr: u8,
+    pub attr: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+    pub companion: Option<SsCompanion>,
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-struct OsPropValues {
-    len: u32,
-    data_type: u32,
-    name_len: u16,
-    name: [u8; DEV_IFACE_GUID.len()],
-    prop_len: u32,
-    prop: [u8; GUID.len()],
+impl Endpoint {
+    pub fn bulk(addr: u8, max_packet_size: u16) -> Self {
+        Self {
+            addr,
+            attr: USB_ENDPOINT_XFER_BULK,
+            max_packet_size,
+            interval: 0,
+            companion: None,
+        }
+    }
+    pub fn companion(mut self, companion: SsCompanion) -> Self {
+        self.companion = Some(companion);
+        self
+    }
+    fn count(&self) -> u32 {
+        1 + self.companion.is_some() as u32
+    }
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(USB_DT_ENDPOINT_SIZE);
+        out.push(libusb::LIBUSB_DT_ENDPOINT);
+        out.push(self.addr);
+        out.push(self.attr);
+        out.extend(self.max_packet_size.to_le_bytes());
+        out.push(self.interval);
+
+        if let Some(comp) = &self.companion {
+            out.push(USB_DT_SS_EP_COMP_SIZE);
+            out.push(libusb::LIBUSB_DT_SS_ENDPOINT_COMPANION);
+            out.push(comp.max_burst);
+            out.push(comp.attr);
+            out.extend(comp.bytes_per_interval.to_le_bytes());
+        }
+    }
 }
 
-const DEV_IFACE_GUID: &'static [u8; 20] = b"DeviceInterfaceGUID\0";
-const GUID: &'static [u8; 39] = b"{F72FE0D4-CBCB-407D-8814-9ED673D0DD6B}\0";
+#[derive(Debug, Clone)]
+pub struct Interface {
+    pub number: u8,
+    pub alt_setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub proto: u8,
+    /// 1-based index into the FunctionFS strings table, 0 for none.
+    pub string: u8,
+    pub endpoints: Vec<Endpoint>,
+}
 
-const OS_PROP_VALUES: OsPropValues = OsPropValues {
-    len: mem::size_of::<OsPropValues>() as u32,
-    data_type: 1,
-    name_len: DEV_IFACE_GUID.len() as u16,
-    name: *DEV_IFACE_GUID,
-    prop_len: GUID.len() as u32,
-    prop: *GUID,
-};
+impl Interface {
+    fn count(&self) -> u32 {
+        1 + self.endpoints.iter().map(Endpoint::count).sum::<u32>()
+    }
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(USB_DT_INTERFACE_SIZE);
+        out.push(libusb::LIBUSB_DT_INTERFACE);
+        out.push(self.number);
+        out.push(self.alt_setting);
+        out.push(self.endpoints.len() as u8);
+        out.push(self.class);
+        out.push(self.subclass);
+        out.push(self.proto);
+        out.push(self.string);
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-struct SsEpCompDesc {
-    length: u8,
-    desc_type: u8,
-    max_burst: u8,
-    attr: u8,
-    bytes_per_interval: u16,
+        for ep in &self.endpoints {
+            ep.write(out);
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-struct SsFuncDesc {
-    interface: InterfaceDesc,
-    source: EndpointDescNoAudio,
-    source_comp: SsEpCompDesc,
-    sink: EndpointDescNoAudio,
-    sink_comp: SsEpCompDesc,
+#[derive(Debug, Clone)]
+pub struct ExtProp {
+    pub data_type: u32,
+    pub name: Vec<u8>,
+    pub data: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-pub struct DescV2 {
-    magic: u32,
-    length: u32,
-    flags: u32,
-    fs_count: u32,
-    hs_count: u32,
-    ss_count: u32,
-    os_count: u32,
-    fs_descs: FuncDesc,
-    hs_descs: FuncDesc,
-    ss_descs: SsFuncDesc,
-    os_header: OsHeader,
-    os_desc: ExtCompatDesc,
-    os_prop_header: OsHeader,
-    os_prop_values: OsPropValues,
+/// Microsoft OS descriptors, the interface number is the one the descriptor
+/// applies to.
+#[derive(Debug, Clone)]
+pub enum OsDesc {
+    ExtCompat {
+        iface: u8,
+        first_iface_num: u8,
+        compat_id: [u8; 8],
+        sub_compat_id: [u8; 8],
+    },
+    ExtProp {
+        iface: u8,
+        props: Vec<ExtProp>,
+    },
 }
 
-impl DescV2 {
-    pub fn as_bytes(&self) -> &[u8] {
-        unsafe {
-            std::slice::from_raw_parts(
-                self as *const DescV2 as *const u8,
-                mem::size_of::<DescV2>(),
-            )
+impl OsDesc {
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            OsDesc::ExtCompat{iface, first_iface_num, compat_id, sub_compat_id} => {
+                out.push(*iface);
+                out.extend(((OS_DESC_HEADER_SIZE + OS_DESC_EXT_COMPAT_SIZE) as u32).to_le_bytes());
+                out.extend(1_u16.to_le_bytes());
+                out.extend(4_u16.to_le_bytes());
+                out.push(1);
+                out.push(0);
+
+                out.push(*first_iface_num);
+                out.push(1);
+                out.extend(compat_id);
+                out.extend(sub_compat_id);
+                out.extend([0; 6]);
+            },
+            OsDesc::ExtProp{iface, props} => {
+                let mut body = Vec::new();
+                for prop in props {
+                    let len = 14 + prop.name.len() + prop.data.len();
+                    body.extend((len as u32).to_le_bytes());
+                    body.extend(prop.data_type.to_le_bytes());
+                    body.extend((prop.name.len() as u16).to_le_bytes());
+                    body.extend(&prop.name);
+                    body.extend((prop.data.len() as u32).to_le_bytes());
+                    body.extend(&prop.data);
+                }
+
+                out.push(*iface);
+                out.extend(((OS_DESC_HEADER_SIZE + body.len()) as u32).to_le_bytes());
+                out.extend(1_u16.to_le_bytes());
+                out.extend(5_u16.to_le_bytes());
+                out.extend((props.len() as u16).to_le_bytes());
+                out.extend(body);
+            },
         }
     }
 }
 
-const FUNCTIONFS_STRINGS_MAGIC: u32 = 2_u32.to_le();
-const FUNCTIONFS_DESCRIPTORS_MAGIC_V2: u32 = 3_u32.to_le();
-
-const FFS_HAS_FS_DESC: u32 = 1_u32.to_le();
-const FFS_HAS_HS_DESC: u32 = 2_u32.to_le();
-const FFS_HAS_SS_DESC: u32 = 4_u32.to_le();
-const FFS_HAS_MS_OS_DESC: u32 = 8_u32.to_le();
-
-const MAX_PACKET_SIZE_FS: u16 = 64_u16.to_le();
-const MAX_PACKET_SIZE_HS: u16 = 512_u16.to_le();
-const MAX_PACKET_SIZE_SS: u16 = 1024_u16.to_le();
-
-const USB_DIR_OUT: u8 = 0;
-const USB_DIR_IN: u8 = 0x80;
-
-const USB_ENDPOINT_XFER_BULK: u8 = 2;
-
-pub const ADB_DESCRIPTOR_V2: DescV2 = DescV2 {
-    magic: FUNCTIONFS_DESCRIPTORS_MAGIC_V2,
-    length: (mem::size_of::<DescV2>() as u32).to_le(),
-    flags: FFS_HAS_FS_DESC | FFS_HAS_HS_DESC |
-           FFS_HAS_SS_DESC | FFS_HAS_MS_OS_DESC,
-    fs_count: 3_u32.to_le(),
-    hs_count: 3_u32.to_le(),
-    ss_count: 5_u32.to_le(),
-    os_count: 2_u32.to_le(),
-    fs_descs: ADB_FS_DESC,
-    hs_descs: ADB_HS_DESC,
-    ss_descs: ADB_SS_DESC,
-    os_header: OS_DESC_HEADER,
-    os_desc: OS_DESC_COMPAT,
-    os_prop_header: OS_PROP_HEADER,
-    os_prop_values: OS_PROP_VALUES,
-};
-
-const ADB_FS_DESC: FuncDesc = FuncDesc {
-    interface: ADB_INTERFACE,
-    source: EndpointDescNoAudio {
-        length: mem::size_of::<EndpointDescNoAudio>() as u8,
-        desc_type: libusb::LIBUSB_DT_ENDPOINT,
-        addr: 1 | USB_DIR_OUT,
-        attr: USB_ENDPOINT_XFER_BULK,
-        max_packet_size: MAX_PACKET_SIZE_FS,
-        interval: 0,
-    },
-    sink: EndpointDescNoAudio {
-        length: mem::size_of::<EndpointDescNoAudio>() as u8,
-        desc_type: libusb::LIBUSB_DT_ENDPOINT,
-        addr: 2 | USB_DIR_IN,
-        attr: USB_ENDPOINT_XFER_BULK,
-        max_packet_size: MAX_PACKET_SIZE_FS,
-        interval: 0,
-    },
-};
-
-const ADB_HS_DESC: FuncDesc = FuncDesc {
-    interface: ADB_INTERFACE,
-    source: EndpointDescNoAudio {
-        length: mem::size_of::<EndpointDescNoAudio>() as u8,
-        desc_type: libusb::LIBUSB_DT_ENDPOINT,
-        addr: 1 | USB_DIR_OUT,
-        attr: USB_ENDPOINT_XFER_BULK,
-        max_packet_size: MAX_PACKET_SIZE_HS,
-        interval: 0,
-    },
-    sink: EndpointDescNoAudio {
-        length: mem::size_of::<EndpointDescNoAudio>() as u8,
-        desc_type: libusb::LIBUSB_DT_ENDPOINT,
-        addr: 2 | USB_DIR_IN,
-        attr: USB_ENDPOINT_XFER_BULK,
-        max_packet_size: MAX_PACKET_SIZE_HS,
-        interval: 0,
-    },
-};
-
-const ADB_SS_DESC: SsFuncDesc = SsFuncDesc {
-    interface: ADB_INTERFACE,
-    source: EndpointDescNoAudio {
-        length: mem::size_of::<EndpointDescNoAudio>() as u8,
-        desc_type: libusb::LIBUSB_DT_ENDPOINT,
-        addr: 1 | USB_DIR_OUT,
-        attr: USB_ENDPOINT_XFER_BULK,
-        max_packet_size: MAX_PACKET_SIZE_SS,
-        interval: 0,
-    },
-    source_comp: SsEpCompDesc {
-        length: mem::size_of::<SsEpCompDesc>() as u8,
-        desc_type: libusb::LIBUSB_DT_SS_ENDPOINT_COMPANION,
-        max_burst: 4,
-        attr: 0,
-        bytes_per_interval: 0,
-    },
-    sink: EndpointDescNoAudio {
-        length: mem::size_of::<EndpointDescNoAudio>() as u8,
-        desc_type: libusb::LIBUSB_DT_ENDPOINT,
-        addr: 2 | USB_DIR_IN,
-        attr: USB_ENDPOINT_XFER_BULK,
-        max_packet_size: MAX_PACKET_SIZE_SS,
-        interval: 0,
-    },
-    sink_comp: SsEpCompDesc {
-        length: mem::size_of::<SsEpCompDesc>() as u8,
-        desc_type: libusb::LIBUSB_DT_SS_ENDPOINT_COMPANION,
+/// Builder for the FunctionFS v2 descriptors blob written to ep0.
+///
+/// Lengths, descriptor counts and flags are derived from what was added.
+#[derive(Debug, Clone, Default)]
+pub struct FfsDescriptors {
+    speeds: Vec<(Speed, Vec<Interface>)>,
+    os_descs: Vec<OsDesc>,
+}
+
+impl FfsDescriptors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn speed(mut self, speed: Speed, interfaces: Vec<Interface>) -> Self {
+        self.speeds.push((speed, interfaces));
+        self
+    }
+    pub fn os_desc(mut self, desc: OsDesc) -> Self {
+        self.os_descs.push(desc);
+        self
+    }
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut flags = 0;
+        let mut counts = Vec::new();
+        let mut body = Vec::new();
+
+        for speed in [Speed::Full, Speed::High, Speed::Super] {
+            let mut found = self.speeds.iter().filter(|(s, _)| *s == speed);
+            let Some((_, ifaces)) = found.next() else { continue; };
+            if found.next().is_some() {
+                bail!("Descriptors for {:?} speed were added more than once", speed);
+            }
+
+            if speed != Speed::Super {
+                if let Some(ep) = ifaces.iter().flat_map(|i| &i.endpoints).find(|e| e.companion.is_some()) {
+                    bail!("Endpoint {:#x} has a SuperSpeed companion in {:?} speed descriptors", ep.addr, speed);
+                }
+            }
+
+            flags |= speed.flag();
+            counts.push(ifaces.iter().map(Interface::count).sum::<u32>());
+            for iface in ifaces {
+                iface.write(&mut body);
+            }
+        }
+
+        if !self.os_descs.is_empty() {
+            flags |= FFS_HAS_MS_OS_DESC;
+            counts.push(self.os_descs.len() as u32);
+            for desc in &self.os_descs {
+                desc.write(&mut body);
+            }
+        }
+
+        let len = 12 + 4 * counts.len() + body.len();
+        let mut ret = Vec::with_capacity(len);
+        ret.extend(FUNCTIONFS_DESCRIPTORS_MAGIC_V2.to_le_bytes());
+        ret.extend((len as u32).to_le_bytes());
+        ret.extend(flags.to_le_bytes());
+        for count in counts {
+            ret.extend(count.to_le_bytes());
+        }
+        ret.extend(body);
+        Ok(ret)
+    }
+}
+
+/// What goes into the descriptors of the adb function.
+///
+/// Interface numbers are local to a FunctionFS function, the kernel
+/// renumbers them when it builds the composite configuration. Where adb ends
+/// up among other functions is up to the configfs gadget.
+#[derive(Debug, Clone)]
+pub struct AdbLayout {
+    /// Whether to emit the WinUSB compatible ID and interface GUID.
+    pub os_descs: bool,
+}
+
+impl Default for AdbLayout {
+    fn default() -> Self {
+        Self {
+            os_descs: true,
+        }
+    }
+}
+
+fn adb_interface(number: u8, max_packet_size: u16, companion: Option<SsCompanion>) -> Interface {
+    let endpoints = [1 | USB_DIR_OUT, 2 | USB_DIR_IN].into_iter()
+        .map(|addr| {
+            let ep = Endpoint::bulk(addr, max_packet_size);
+            match companion {
+                Some(comp) => ep.companion(comp),
+                None => ep,
+            }
+        })
+        .collect();
+
+    Interface {
+        number,
+        alt_setting: 0,
+        class: ADB_CLASS,
+        subclass: ADB_SUBCLASS,
+        proto: ADB_PROTOCOL,
+        string: 1,
+        endpoints,
+    }
+}
+
+/// Descriptors of the adb function, optionally with WinUSB OS descriptors.
+pub fn adb_descriptors(layout: &AdbLayout) -> FfsDescriptors {
+    let ss_comp = SsCompanion {
         max_burst: 4,
         attr: 0,
         bytes_per_interval: 0,
-    },
-};
-
-const OS_DESC_HEADER: OsHeader = OsHeader {
-    iface: 0,
-    len: (mem::size_of::<OsHeader>() + mem::size_of::<ExtCompatDesc>()) as u32,
-    bcd_ver: 1,
-    idx: 4,
-    count: 1,
-    reserved: 0,
-};
-
-const OS_DESC_COMPAT: ExtCompatDesc = ExtCompatDesc {
-    first_iface_num: 0,
-    res1: 1,
-    compat_id: *b"WINUSB\0\0",
-    sub_compat_id: [0_u8; 8],
-    res_2: [0; 6],
-};
-
-const OS_PROP_HEADER: OsHeader = OsHeader {
-    iface: 0,
-    len: (mem::size_of::<OsHeader>() + mem::size_of::<OsPropValues>()) as u32,
-    bcd_ver: 1,
-    idx: 5,
-    count: 1,
-    reserved: 0,
-};
-
-const IFACE_STRING: &'static [u8; 14] = b"ADB Interface\0";
+    };
+    let ret = FfsDescriptors::new()
+        .speed(Speed::Full, vec![adb_interface(0, MAX_PACKET_SIZE_FS, None)])
+        .speed(Speed::High, vec![adb_interface(0, MAX_PACKET_SIZE_HS, None)])
+        .speed(Speed::Super, vec![adb_interface(0, MAX_PACKET_SIZE_SS, Some(ss_comp))]);
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed)]
-pub struct FfsStringData {
-    magic: u32,
-    length: u32,
-    str_count: u32,
-    lang_count: u32,
-    code: u16,
-    str1: [u8; IFACE_STRING.len()],
+    if !layout.os_descs {
+        return ret;
+    }
+
+    ret.os_desc(OsDesc::ExtCompat {
+        iface: 0,
+        first_iface_num: 0,
+        compat_id: *b"WINUSB\0\0",
+        sub_compat_id: [0; 8],
+    })
+    .os_desc(OsDesc::ExtProp {
+        iface: 0,
+        props: vec![ExtProp {
+            data_type: 1,
+            name: DEV_IFACE_GUID.to_vec(),
+            data: ADB_GUID.to_vec(),
+        }],
+    })
 }
 
-impl FfsStringData {
-    pub fn as_bytes(&self) -> &[u8] {
-        unsafe {
-            std::slice::from_raw_parts(
-                self as *const FfsStringData as *const u8,
-                mem::size_of::<FfsStringData>(),
-            )
+/// Builder for the FunctionFS strings blob written to ep0 after the descriptors.
+///
+/// Every language has to carry the same number of strings, string indices in
+/// the descriptors are 1-based positions into each language's list.
+#[derive(Debug, Clone, Default)]
+pub struct FfsStrings {
+    langs: Vec<(u16, Vec<String>)>,
+}
+
+impl FfsStrings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn lang(mut self, code: u16, strings: Vec<String>) -> Self {
+        self.langs.push((code, strings));
+        self
+    }
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let str_count = self.langs.first()
+            .map(|(_, strings)| strings.len())
+            .unwrap_or(0);
+
+        let mut body = Vec::new();
+        for (code, strings) in &self.langs {
+            if strings.len() != str_count {
+                bail!("Language {:#x} has {} strings, expected {}", code, strings.len(), str_count);
+            }
+            body.extend(code.to_le_bytes());
+            for s in strings {
+                if s.contains('\0') {
+                    bail!("String {:?} for language {:#x} contains a NUL byte", s, code);
+                }
+                body.extend(s.as_bytes());
+                body.push(0);
+            }
         }
+
+        let mut ret = Vec::new();
+        ret.extend(FUNCTIONFS_STRINGS_MAGIC.to_le_bytes());
+        ret.extend(((16 + body.len()) as u32).to_le_bytes());
+        ret.extend((str_count as u32).to_le_bytes());
+        ret.extend((self.langs.len() as u32).to_le_bytes());
+        ret.extend(body);
+        Ok(ret)
     }
 }
 
-pub const ADB_STRINGS: FfsStringData = FfsStringData {
-    magic: FUNCTIONFS_STRINGS_MAGIC,
-    length: (mem::size_of::<FfsStringData>() as u32).to_le(),
-    str_count: 1,
-    lang_count: 1,
+pub const LANG_EN_US: u16 = 0x409;
+
+/// USB strings of a single language.
+///
+/// Serial number, manufacturer and product belong to the gadget's device
+/// descriptor, so they only take effect when the gadget is configured through
+/// configfs, the interface name goes into the FunctionFS strings table.
+#[derive(Debug, Clone)]
+pub struct LangStrings {
+    pub code: u16,
+    pub serial: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub interface: String,
+}
+
+impl LangStrings {
+    pub fn new(code: u16) -> Self {
+        Self {
+            code,
+            serial: None,
+            manufacturer: None,
+            product: None,
+            interface: "ADB Interface".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UsbStrings {
+    pub langs: Vec<LangStrings>,
+}
+
+impl Default for UsbStrings {
+    fn default() -> Self {
+        let mut en = LangStrings::new(LANG_EN_US);
+        en.serial = default_serial();
+        Self {
+            langs: vec![en],
+        }
+    }
+}
+
+impl UsbStrings {
+    pub fn lang_mut(&mut self, code: u16) -> &mut LangStrings {
+        let idx = match self.langs.iter().position(|l| l.code == code) {
+            Some(idx) => idx,
+            None => {
+                self.langs.push(LangStrings::new(code));
+                self.langs.len() - 1
+            }
+        };
+        &mut self.langs[idx]
+    }
+    pub fn ffs_strings(&self) -> FfsStrings {
+        self.langs.iter()
+            .fold(FfsStrings::new(), |ffs, l| ffs.lang(l.code, vec![l.interface.clone()]))
+    }
+}
+
+/// Picks a serial number that stays the same across reboots of a board.
+pub fn default_serial() -> Option<String> {
+    if let Ok(serial) = fs::read_to_string("/proc/device-tree/serial-number") {
+        let serial = serial.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        if !serial.is_empty() {
+            return Some(serial.to_string());
+        }
+    }
+
+    let id = fs::read_to_string("/etc/machine-id").ok()?;
+    let id = id.trim();
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.chars().take(16).collect())
+    }
+}
 
-    code: 0x409_u16.to_le(),
-    str1: *IFACE_STRING,
-};
+/// The adb function's FunctionFS endpoints.
+///
+/// ep0 has to stay open for as long as the function should exist.
+pub struct FunctionFs {
+    _ep0: File,
+    ep_out: Option<File>,
+    ep_in: Option<File>,
+    aio: bool,
+}
+
+impl FunctionFs {
+    /// Writes descriptors and strings to ep0 and opens the bulk endpoints.
+    pub fn open(path: &Path, descs: &FfsDescriptors, strings: &FfsStrings, aio: bool) -> Result<Self> {
+        let mut ep0 = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(false)
+            .open(path.join("ep0"))
+            .context("Failed to open ep0")?;
+
+        ep0.write_all(&descs.to_bytes()?)
+            .context("Failed to write descriptors")?;
+        ep0.write_all(&strings.to_bytes()?)
+            .context("Failed to write strings")?;
+
+        let ep_out = OpenOptions::new()
+            .read(true)
+            .write(false)
+            .create(false)
+            .open(path.join("ep1"))
+            .context("Failed to open ep1")?;
+
+        let ep_in = OpenOptions::new()
+            .read(false)
+            .write(true)
+            .create(false)
+            .open(path.join("ep2"))
+            .context("Failed to open ep2")?;
+
+        Ok(Self {
+            _ep0: ep0,
+            ep_out: Some(ep_out),
+            ep_in: Some(ep_in),
+            aio,
+        })
+    }
+}
+
+impl Transport for FunctionFs {
+    fn kind(&self) -> &'static str {
+        "usb"
+    }
+    fn split(&mut self) -> Result<(Reader, Writer)> {
+        let ep_out = self.ep_out.take().context("Transport was already split")?;
+        let ep_in = self.ep_in.take().context("Transport was already split")?;
+
+        if self.aio {
+            Ok((Box::new(aio::AioReader::new(ep_out, aio::DEFAULT_DEPTH, MAXDATA as usize)
+                    .context("Failed to set up AIO for ep1")?),
+                Box::new(aio::AioWriter::new(ep_in, aio::DEFAULT_DEPTH)
+                    .context("Failed to set up AIO for ep2")?)))
+        } else {
+            Ok((Box::new(ep_out), Box::new(ep_in)))
+        }
+    }
+    fn announces(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The blob radbd used to send, assembled from packed structs.
+    const ADB_DESCRIPTOR_V2: [u8; 228] = [
+        0x03, 0x00, 0x00, 0x00, 0xe4, 0x00, 0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00,
+        0x03, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x09, 0x04, 0x00, 0x00,
+        0x02, 0xff, 0x42, 0x01, 0x01, 0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, 0x07, 0x05, 0x82, 0x02,
+        0x40, 0x00, 0x00, 0x09, 0x04, 0x00, 0x00, 0x02, 0xff, 0x42, 0x01, 0x01, 0x07, 0x05, 0x01, 0x02,
+        0x00, 0x02, 0x00, 0x07, 0x05, 0x82, 0x02, 0x00, 0x02, 0x00, 0x09, 0x04, 0x00, 0x00, 0x02, 0xff,
+        0x42, 0x01, 0x01, 0x07, 0x05, 0x01, 0x02, 0x00, 0x04, 0x00, 0x06, 0x30, 0x04, 0x00, 0x00, 0x00,
+        0x07, 0x05, 0x82, 0x02, 0x00, 0x04, 0x00, 0x06, 0x30, 0x04, 0x00, 0x00, 0x00, 0x00, 0x23, 0x00,
+        0x00, 0x00, 0x01, 0x00, 0x04, 0x00, 0x01, 0x00, 0x00, 0x01, 0x57, 0x49, 0x4e, 0x55, 0x53, 0x42,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x54, 0x00, 0x00, 0x00, 0x01, 0x00, 0x05, 0x00, 0x01, 0x00, 0x49, 0x00, 0x00, 0x00, 0x01,
+        0x00, 0x00, 0x00, 0x14, 0x00, 0x44, 0x65, 0x76, 0x69, 0x63, 0x65, 0x49, 0x6e, 0x74, 0x65, 0x72,
+        0x66, 0x61, 0x63, 0x65, 0x47, 0x55, 0x49, 0x44, 0x00, 0x27, 0x00, 0x00, 0x00, 0x7b, 0x46, 0x37,
+        0x32, 0x46, 0x45, 0x30, 0x44, 0x34, 0x2d, 0x43, 0x42, 0x43, 0x42, 0x2d, 0x34, 0x30, 0x37, 0x44,
+        0x2d, 0x38, 0x38, 0x31, 0x34, 0x2d, 0x39, 0x45, 0x44, 0x36, 0x37, 0x33, 0x44, 0x30, 0x44, 0x44,
+        0x36, 0x42, 0x7d, 0x00,
+    ];
+
+    #[test]
+    fn adb_descriptors_match_packed_layout() {
+        let blob = adb_descriptors(&AdbLayout::default()).to_bytes().unwrap();
+        assert_eq!(blob, ADB_DESCRIPTOR_V2);
+
+        // Local interface 0 everywhere, bFirstInterfaceNumber included
+        let os = 28 + 23 + 23 + 35;
+        assert_eq!((blob[30], blob[os], blob[os + 11], blob[os + 35]), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn layout_drops_os_descs() {
+        let layout = AdbLayout {
+            os_descs: false,
+        };
+        let blob = adb_descriptors(&layout).to_bytes().unwrap();
+        assert_eq!(blob[8..12], (FFS_HAS_FS_DESC | FFS_HAS_HS_DESC | FFS_HAS_SS_DESC).to_le_bytes());
+        assert_eq!(blob.len(), 24 + 23 + 23 + 35);
+    }
+
+    #[test]
+    fn counts_and_flags_follow_contents() {
+        let blob = FfsDescriptors::new()
+            .speed(Speed::High, vec![adb_interface(0, MAX_PACKET_SIZE_HS, None)])
+            .to_bytes()
+            .unwrap();
+
+        assert_eq!(blob.len(), 16 + 9 + 7 + 7);
+        assert_eq!(blob[4..8], (blob.len() as u32).to_le_bytes());
+        assert_eq!(blob[8..12], FFS_HAS_HS_DESC.to_le_bytes());
+        assert_eq!(blob[12..16], 3_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn companion_outside_superspeed_is_rejected() {
+        let comp = SsCompanion {
+            max_burst: 0,
+            attr: 0,
+            bytes_per_interval: 0,
+        };
+        let ret = FfsDescriptors::new()
+            .speed(Speed::Full, vec![adb_interface(0, MAX_PACKET_SIZE_FS, Some(comp))])
+            .to_bytes();
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn adb_strings_match_packed_layout() {
+        let blob = UsbStrings::default().ffs_strings().to_bytes().unwrap();
+        let mut expected = vec![
+            0x02, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x09, 0x04,
+        ];
+        expected.extend(b"ADB Interface\0");
+        assert_eq!(blob, expected);
+    }
+
+    #[test]
+    fn strings_need_the_same_count_per_language() {
+        let ret = FfsStrings::new()
+            .lang(LANG_EN_US, vec!["a".to_string()])
+            .lang(0x407, vec!["a".to_string(), "b".to_string()])
+            .to_bytes();
+        assert!(ret.is_err());
+    }
+}